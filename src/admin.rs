@@ -0,0 +1,57 @@
+use anyhow::Result;
+use http_body_util::Full;
+use hyper::{body::Bytes, header, Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::sync::Arc;
+
+use crate::{metrics, state::State, utils};
+
+async fn handle_request(
+    req: Request<hyper::body::Incoming>,
+    state: Arc<State>,
+) -> Result<Response<Full<Bytes>>> {
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => {
+            let mut response = Response::new(Full::new(Bytes::from(metrics::render(&state))));
+            response.headers_mut().insert(
+                header::CONTENT_TYPE,
+                header::HeaderValue::from_static("text/plain; version=0.0.4"),
+            );
+            Ok(response)
+        }
+        _ => Ok(utils::status_response(StatusCode::NOT_FOUND, "Not Found")),
+    }
+}
+
+/// Serves the admin endpoints on their own listener so they are never exposed with the gateway.
+pub async fn serve(address: &str, state: Arc<State>) -> Result<()> {
+    let addr: std::net::SocketAddr = address.parse()?;
+    let listener = tokio::net::TcpListener::bind(&addr).await?;
+    println!("Admin listening on http://{addr}");
+
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    println!("Error accepting admin connection: {err:?}");
+                    continue;
+                }
+            };
+            let state = state.clone();
+            tokio::spawn(async move {
+                if let Err(err) = hyper::server::conn::http1::Builder::new()
+                    .serve_connection(
+                        TokioIo::new(stream),
+                        hyper::service::service_fn(move |req| handle_request(req, state.clone())),
+                    )
+                    .await
+                {
+                    println!("Error serving admin connection: {err:?}");
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
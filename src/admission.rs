@@ -0,0 +1,232 @@
+use http_body_util::Full;
+use hyper::{
+    body::Bytes,
+    header::{self, HeaderValue},
+    Response, StatusCode,
+};
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+use tokio::sync::oneshot;
+
+use crate::{config, permission::PermissionSet, utils};
+
+// queued requests, the last entry is the highest priority and the oldest of that priority
+type Queue = BTreeMap<(u32, Reverse<u64>), oneshot::Sender<Permit>>;
+
+#[derive(Debug)]
+struct Inner {
+    in_flight: usize,
+    queue: Queue,
+    next_seq: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Rejected {
+    // queue full of higher priorities, or pushed out by one
+    Shed,
+    // waited longer than `queue_timeout_ms`
+    Timeout,
+}
+
+impl Rejected {
+    pub fn response(&self) -> Response<Full<Bytes>> {
+        let mut response =
+            utils::status_response(StatusCode::SERVICE_UNAVAILABLE, "Upstream overloaded");
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(1));
+        response
+    }
+}
+
+/// Concurrency limit in front of an upstream. Requests over the limit wait in a bounded queue
+/// served highest priority first, when it is full the lowest priority requests are shed.
+#[derive(Debug)]
+pub struct Admission {
+    config: config::Admission,
+    inner: Mutex<Inner>,
+}
+
+/// A slot on the upstream, handed to the next queued request when dropped. Queued requests
+/// receive the permit itself, so one that gives up after the handover still gives it back.
+#[derive(Debug)]
+pub struct Permit {
+    admission: Arc<Admission>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.admission.release();
+    }
+}
+
+impl Admission {
+    pub fn new(config: config::Admission) -> Self {
+        Admission {
+            config,
+            inner: Mutex::new(Inner {
+                in_flight: 0,
+                queue: BTreeMap::new(),
+                next_seq: 0,
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// 0 for users without any of the configured permissions, the first class is the highest.
+    pub fn priority(&self, permissions: &PermissionSet) -> u32 {
+        let classes = &self.config.priorities;
+        classes
+            .iter()
+            .position(|class| permissions.holds(class))
+            .map_or(0, |index| (classes.len() - index) as u32)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.lock().in_flight
+    }
+
+    pub fn queued(&self) -> usize {
+        self.lock().queue.len()
+    }
+
+    fn release(self: &Arc<Self>) {
+        let mut inner = self.lock();
+        match inner.queue.pop_last() {
+            // the slot goes straight to the next waiter, one that is gone drops the permit
+            // which hands it on again
+            Some((_, waiter)) => {
+                drop(inner);
+                let _ = waiter.send(Permit {
+                    admission: self.clone(),
+                });
+            }
+            None => inner.in_flight -= 1,
+        }
+    }
+
+    pub async fn acquire(self: &Arc<Self>, priority: u32) -> Result<Permit, Rejected> {
+        let (key, receiver) = {
+            let mut inner = self.lock();
+
+            if inner.in_flight < self.config.max_concurrency && inner.queue.is_empty() {
+                inner.in_flight += 1;
+                return Ok(Permit {
+                    admission: self.clone(),
+                });
+            }
+
+            if inner.queue.len() >= self.config.max_queue {
+                match inner.queue.first_key_value() {
+                    // push out the newest request of the lowest priority
+                    Some((&(lowest, _), _)) if lowest < priority => {
+                        inner.queue.pop_first();
+                    }
+                    _ => return Err(Rejected::Shed),
+                }
+            }
+
+            let key = (priority, Reverse(inner.next_seq));
+            inner.next_seq += 1;
+            let (sender, receiver) = oneshot::channel();
+            inner.queue.insert(key, sender);
+            (key, receiver)
+        };
+
+        let waited = tokio::time::timeout(
+            Duration::from_millis(self.config.queue_timeout_ms),
+            receiver,
+        )
+        .await;
+
+        match waited {
+            Ok(Ok(permit)) => Ok(permit),
+            Ok(Err(_)) => Err(Rejected::Shed),
+            // a permit handed over right as the deadline passed was dropped with the receiver
+            Err(_) => {
+                self.lock().queue.remove(&key);
+                Err(Rejected::Timeout)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn admission() -> Arc<Admission> {
+        Arc::new(Admission::new(config::Admission {
+            max_concurrency: 1,
+            max_queue: 1,
+            queue_timeout_ms: 1000,
+            priorities: vec!["premium".into()],
+        }))
+    }
+
+    #[test]
+    fn test_priority() {
+        let admission = admission();
+        assert_eq!(
+            admission.priority(&PermissionSet::new(["news", "premium"])),
+            1
+        );
+        assert_eq!(admission.priority(&PermissionSet::new(["news"])), 0);
+    }
+
+    #[tokio::test]
+    async fn test_lowest_priority_is_shed() {
+        let admission = admission();
+        let permit = admission.acquire(0).await.unwrap();
+
+        let low = tokio::spawn({
+            let admission = admission.clone();
+            async move { admission.acquire(0).await.map(|_| ()) }
+        });
+        while admission.queued() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let high = tokio::spawn({
+            let admission = admission.clone();
+            async move { admission.acquire(1).await.map(|_| ()) }
+        });
+        assert_eq!(low.await.unwrap(), Err(Rejected::Shed));
+
+        // a full queue of higher priorities turns lower ones away right away
+        assert_eq!(admission.acquire(0).await.unwrap_err(), Rejected::Shed);
+
+        drop(permit);
+        assert_eq!(high.await.unwrap(), Ok(()));
+        assert_eq!(admission.in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_handed_over_slot_is_not_lost() {
+        let admission = admission();
+        let permit = admission.acquire(0).await.unwrap();
+
+        let waiter = tokio::spawn({
+            let admission = admission.clone();
+            async move { admission.acquire(0).await.map(|_| ()) }
+        });
+        while admission.queued() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        // the slot is handed over, then the waiter goes away before it runs, e.g. the client
+        // disconnected
+        drop(permit);
+        waiter.abort();
+        assert!(waiter.await.unwrap_err().is_cancelled());
+        assert_eq!(admission.in_flight(), 0);
+        assert!(admission.acquire(0).await.is_ok());
+    }
+}
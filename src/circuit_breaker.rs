@@ -0,0 +1,274 @@
+use std::{
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use http_body_util::Full;
+use hyper::{
+    body::Bytes,
+    header::{self, HeaderValue},
+    Response, StatusCode,
+};
+
+use crate::{config, utils};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+/// Returned while the breaker is open, answered with a 503 and `Retry-After`.
+#[derive(Debug)]
+pub struct CircuitOpen {
+    pub name: String,
+    pub retry_after: Duration,
+}
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is unavailable", self.name)
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+impl CircuitOpen {
+    pub fn response(&self) -> Response<Full<Bytes>> {
+        let mut response =
+            utils::status_response(StatusCode::SERVICE_UNAVAILABLE, &self.to_string());
+        // rounded up so clients never come back while the breaker is still open
+        let retry_after =
+            self.retry_after.as_secs() + u64::from(self.retry_after.subsec_nanos() > 0);
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        response
+    }
+}
+
+/// A call let through the breaker. Dropped without `release`, e.g. when the caller was
+/// cancelled, it counts as a failure so a half-open slot is never left taken.
+#[derive(Debug)]
+#[must_use]
+pub struct BreakerPermit<'a> {
+    breaker: &'a CircuitBreaker,
+    released: bool,
+}
+
+impl BreakerPermit<'_> {
+    pub fn release(mut self, success: bool) {
+        self.released = true;
+        self.breaker.record(success);
+    }
+
+    /// Gives the slot back without an outcome, the call never went out.
+    pub fn cancel(mut self) {
+        self.released = true;
+        let mut inner = self.breaker.lock();
+        if inner.state == BreakerState::HalfOpen {
+            inner.half_open_in_flight = inner.half_open_in_flight.saturating_sub(1);
+        }
+    }
+}
+
+impl Drop for BreakerPermit<'_> {
+    fn drop(&mut self) {
+        if !self.released {
+            self.breaker.record(false);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: BreakerState,
+    consecutive_failures: u32,
+    opened_at: Instant,
+    half_open_in_flight: u32,
+    half_open_successes: u32,
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    name: String,
+    config: config::CircuitBreaker,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &str, config: config::CircuitBreaker) -> Self {
+        CircuitBreaker {
+            name: name.to_string(),
+            config,
+            inner: Mutex::new(Inner {
+                state: BreakerState::Closed,
+                consecutive_failures: 0,
+                opened_at: Instant::now(),
+                half_open_in_flight: 0,
+                half_open_successes: 0,
+            }),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        // the state stays consistent even if a holder panicked
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn open(&self, inner: &mut Inner) {
+        inner.state = BreakerState::Open;
+        inner.opened_at = Instant::now();
+        inner.consecutive_failures = 0;
+        println!("Circuit breaker {} opened", self.name);
+    }
+
+    pub fn state(&self) -> BreakerState {
+        self.lock().state
+    }
+
+    /// Lets a call through, or says how long until the breaker will try again.
+    fn try_acquire(&self) -> Result<(), CircuitOpen> {
+        let mut inner = self.lock();
+        let open_for = Duration::from_millis(self.config.open_ms);
+
+        if inner.state == BreakerState::Open {
+            let elapsed = inner.opened_at.elapsed();
+            if elapsed < open_for {
+                return Err(CircuitOpen {
+                    name: self.name.clone(),
+                    retry_after: open_for - elapsed,
+                });
+            }
+            inner.state = BreakerState::HalfOpen;
+            inner.half_open_in_flight = 0;
+            inner.half_open_successes = 0;
+        }
+
+        if inner.state == BreakerState::HalfOpen {
+            if inner.half_open_in_flight >= self.config.half_open_max_requests.max(1) {
+                return Err(CircuitOpen {
+                    name: self.name.clone(),
+                    retry_after: Duration::from_secs(1),
+                });
+            }
+            inner.half_open_in_flight += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Lets a call through, its outcome is recorded when the permit is released or dropped.
+    pub fn acquire(&self) -> Result<BreakerPermit<'_>, CircuitOpen> {
+        self.try_acquire()?;
+        Ok(BreakerPermit {
+            breaker: self,
+            released: false,
+        })
+    }
+
+    /// Records the outcome of a call that got through `try_acquire`.
+    fn record(&self, success: bool) {
+        let mut inner = self.lock();
+        match (inner.state, success) {
+            (BreakerState::Closed, true) => inner.consecutive_failures = 0,
+            (BreakerState::Closed, false) => {
+                inner.consecutive_failures += 1;
+                if inner.consecutive_failures >= self.config.failure_threshold.max(1) {
+                    self.open(&mut inner);
+                }
+            }
+            (BreakerState::HalfOpen, true) => {
+                inner.half_open_in_flight = inner.half_open_in_flight.saturating_sub(1);
+                inner.half_open_successes += 1;
+                if inner.half_open_successes >= self.config.success_threshold.max(1) {
+                    inner.state = BreakerState::Closed;
+                    inner.consecutive_failures = 0;
+                    println!("Circuit breaker {} closed", self.name);
+                }
+            }
+            (BreakerState::HalfOpen, false) => self.open(&mut inner),
+            // calls let through before the breaker opened
+            (BreakerState::Open, _) => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let breaker = CircuitBreaker::new(
+            "test",
+            config::CircuitBreaker {
+                failure_threshold: 2,
+                open_ms: 0,
+                half_open_max_requests: 1,
+                success_threshold: 1,
+            },
+        );
+
+        breaker.try_acquire().unwrap();
+        breaker.record(false);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        breaker.try_acquire().unwrap();
+        breaker.record(false);
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        // open_ms has passed so a single probe is let through
+        breaker.try_acquire().unwrap();
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        assert!(breaker.try_acquire().is_err());
+        breaker.record(true);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn test_open_breaker_fails_fast() {
+        let breaker = CircuitBreaker::new(
+            "test",
+            config::CircuitBreaker {
+                failure_threshold: 1,
+                open_ms: 60000,
+                ..Default::default()
+            },
+        );
+
+        breaker.record(false);
+        let err = breaker.try_acquire().unwrap_err();
+        assert!(err.retry_after > Duration::from_secs(59));
+    }
+
+    #[test]
+    fn test_dropped_permit_frees_the_probe() {
+        let breaker = CircuitBreaker::new(
+            "test",
+            config::CircuitBreaker {
+                failure_threshold: 1,
+                open_ms: 0,
+                half_open_max_requests: 1,
+                success_threshold: 1,
+            },
+        );
+
+        breaker.acquire().unwrap().release(false);
+        let probe = breaker.acquire().unwrap();
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        assert!(breaker.acquire().is_err());
+
+        // e.g. the client went away, the breaker can probe again instead of staying half open
+        drop(probe);
+        breaker.acquire().unwrap().cancel();
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        breaker.acquire().unwrap().release(true);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+}
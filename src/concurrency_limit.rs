@@ -0,0 +1,210 @@
+use http_body_util::Full;
+use hyper::{
+    body::Bytes,
+    header::{self, HeaderValue},
+    Response, StatusCode,
+};
+use std::{
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use crate::{config, utils};
+
+// weight of a new sample in the long term latency average
+const LATENCY_SMOOTHING: f64 = 0.05;
+
+// below one nothing gets through to ever grow the limit again
+fn min_limit(config: &config::AdaptiveConcurrency) -> f64 {
+    config.min_limit.max(1) as f64
+}
+
+#[derive(Debug)]
+struct Inner {
+    limit: f64,
+    in_flight: usize,
+    // long term average, samples well above it mean the upstream is slowing down
+    latency_ms: f64,
+    // requests started before the last decrease saw the old limit and must not cut it again
+    decreased: Option<Instant>,
+}
+
+/// Concurrency limit that adapts to the upstream: it grows by one while requests are fast and
+/// the limit is being used, and shrinks by `backoff_ratio` on failures and slow responses, once per burst.
+#[derive(Debug)]
+pub struct ConcurrencyLimit {
+    config: config::AdaptiveConcurrency,
+    inner: Mutex<Inner>,
+}
+
+/// A request counted against the limit. Dropped without `release`, e.g. when the total
+/// timeout cancelled it, it counts as a failure.
+#[derive(Debug)]
+pub struct LimitPermit {
+    limit: Arc<ConcurrencyLimit>,
+    started: Instant,
+    released: bool,
+}
+
+impl LimitPermit {
+    pub fn release(mut self, success: bool) {
+        self.released = true;
+        self.limit
+            .record(self.started, self.started.elapsed(), success);
+    }
+}
+
+impl Drop for LimitPermit {
+    fn drop(&mut self) {
+        if !self.released {
+            self.limit
+                .record(self.started, self.started.elapsed(), false);
+        }
+    }
+}
+
+/// Returned when the upstream already has as many requests in flight as it is allowed.
+#[derive(Debug)]
+pub struct LimitExceeded;
+
+impl LimitExceeded {
+    pub fn response(&self) -> Response<Full<Bytes>> {
+        let mut response =
+            utils::status_response(StatusCode::SERVICE_UNAVAILABLE, "Upstream overloaded");
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(1));
+        response
+    }
+}
+
+impl ConcurrencyLimit {
+    pub fn new(config: config::AdaptiveConcurrency) -> Self {
+        let limit =
+            (config.initial_limit as f64).clamp(min_limit(&config), config.max_limit as f64);
+        ConcurrencyLimit {
+            config,
+            inner: Mutex::new(Inner {
+                limit,
+                in_flight: 0,
+                latency_ms: 0.0,
+                decreased: None,
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn limit(&self) -> usize {
+        self.lock().limit as usize
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.lock().in_flight
+    }
+
+    pub fn try_acquire(self: &Arc<Self>) -> Result<LimitPermit, LimitExceeded> {
+        let mut inner = self.lock();
+        if inner.in_flight >= inner.limit as usize {
+            return Err(LimitExceeded);
+        }
+        inner.in_flight += 1;
+        Ok(LimitPermit {
+            limit: self.clone(),
+            started: Instant::now(),
+            released: false,
+        })
+    }
+
+    fn record(&self, started: Instant, latency: Duration, success: bool) {
+        let mut inner = self.lock();
+        let latency_ms = latency.as_secs_f64() * 1000.0;
+        let slow =
+            inner.latency_ms > 0.0 && latency_ms > inner.latency_ms * self.config.latency_tolerance;
+
+        if !success || slow {
+            if inner.decreased.is_none_or(|decreased| started >= decreased) {
+                inner.limit =
+                    (inner.limit * self.config.backoff_ratio).max(min_limit(&self.config));
+                inner.decreased = Some(Instant::now());
+            }
+        } else if inner.in_flight * 2 >= inner.limit as usize {
+            // only grow while the limit is actually what holds traffic back
+            inner.limit = (inner.limit + 1.0).min(self.config.max_limit as f64);
+        }
+
+        // failures are often fast and would drag the average down
+        if success {
+            inner.latency_ms = if inner.latency_ms == 0.0 {
+                latency_ms
+            } else {
+                inner.latency_ms * (1.0 - LATENCY_SMOOTHING) + latency_ms * LATENCY_SMOOTHING
+            };
+        }
+        inner.in_flight -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn finish(mut permit: LimitPermit, latency_ms: u64, success: bool) {
+        permit.released = true;
+        permit
+            .limit
+            .record(permit.started, Duration::from_millis(latency_ms), success);
+    }
+
+    #[test]
+    fn test_adaptive_limit() {
+        let limit = Arc::new(ConcurrencyLimit::new(config::AdaptiveConcurrency {
+            initial_limit: 2,
+            min_limit: 1,
+            max_limit: 3,
+            latency_tolerance: 2.0,
+            backoff_ratio: 0.5,
+        }));
+
+        let first = limit.try_acquire().unwrap();
+        let second = limit.try_acquire().unwrap();
+        assert!(limit.try_acquire().is_err());
+
+        // a fast response while the limit is in use grows it
+        finish(first, 10, true);
+        assert_eq!(limit.limit(), 3);
+        finish(second, 10, true);
+
+        // slow responses and failures shrink it down to `min_limit`
+        finish(limit.try_acquire().unwrap(), 100, true);
+        assert_eq!(limit.limit(), 1);
+        drop(limit.try_acquire().unwrap());
+        assert_eq!(limit.limit(), 1);
+        assert_eq!(limit.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_burst_decreases_once() {
+        let limit = Arc::new(ConcurrencyLimit::new(config::AdaptiveConcurrency {
+            initial_limit: 8,
+            min_limit: 1,
+            max_limit: 8,
+            latency_tolerance: 2.0,
+            backoff_ratio: 0.5,
+        }));
+
+        // requests already in flight when the limit drops do not drop it again
+        let burst: Vec<_> = (0..4).map(|_| limit.try_acquire().unwrap()).collect();
+        for permit in burst {
+            finish(permit, 10, false);
+        }
+        assert_eq!(limit.limit(), 4);
+
+        // a request started after the decrease counts again
+        finish(limit.try_acquire().unwrap(), 10, false);
+        assert_eq!(limit.limit(), 2);
+        assert_eq!(limit.in_flight(), 0);
+    }
+}
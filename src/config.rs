@@ -1,11 +1,33 @@
+use anyhow::Result;
 use hyper::Uri;
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::permission::RequiredPermission;
 
 pub type Permission = String;
 
 pub struct Config {
     pub listening_address: String,
 
-    pub permission_url: Uri,
+    pub permission_url: Option<Uri>,
+
+    pub permission_provider: PermissionProvider,
+
+    pub insecure_unverified_tokens: bool,
+
+    pub permission_cache: Option<PermissionCache>,
+
+    pub permission_webhook: Option<PermissionWebhook>,
+
+    pub degraded_mode: Option<DegradedMode>,
+
+    pub sessions: Sessions,
+
+    pub session_snapshot: Option<SessionSnapshot>,
+
+    pub peers: Option<Peers>,
 
     pub socket_encryption_key: String,
 
@@ -13,4 +35,661 @@ pub struct Config {
 
     pub access_token_jwt_cookie_name: String,
     pub refresh_token_jwt_cookie_name: String,
+
+    pub routes: Vec<Route>,
+    pub anonymous_permissions: Vec<Permission>,
+
+    pub roles: HashMap<String, Vec<Permission>>,
+
+    pub login_redirect: Option<LoginRedirect>,
+
+    pub logout: Option<Logout>,
+
+    pub trusted_proxies: Vec<IpNet>,
+
+    pub retry_budget: RetryBudget,
+
+    pub upstreams: HashMap<String, UpstreamPool>,
+
+    pub circuit_breakers: CircuitBreakers,
+
+    pub admission: Option<Admission>,
+
+    pub adaptive_concurrency: Option<AdaptiveConcurrency>,
+
+    pub admin_listening_address: Option<String>,
+}
+
+/// Optional settings loaded from the YAML file pointed to by `$CONFIG_FILE`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct FileConfig {
+    // where permissions come from, the permission service at `$PERMISSION_URL` by default
+    pub permission_provider: PermissionProvider,
+
+    // token signatures are never checked, so providers that grant from the token's claims
+    // (`static`, `jwt_claim`) only start with this set, e.g. to run locally
+    pub insecure_unverified_tokens: bool,
+
+    // shares permissions between the sessions of a user
+    pub permission_cache: Option<PermissionCache>,
+
+    // lets the permission service push permission changes
+    pub permission_webhook: Option<PermissionWebhook>,
+
+    // keeps sessions going while the permission provider fails
+    pub degraded_mode: Option<DegradedMode>,
+
+    // how long sessions are kept and how many
+    pub sessions: Sessions,
+
+    // keeps sessions across restarts
+    pub session_snapshot: Option<SessionSnapshot>,
+
+    // other replicas sessions are kept in sync with
+    pub peers: Option<Peers>,
+
+    pub routes: Vec<Route>,
+
+    // permissions forwarded for requests that are not tied to a session
+    pub anonymous_permissions: Vec<Permission>,
+
+    // permissions added for every role a user holds, roles may include other roles
+    pub roles: HashMap<String, Vec<Permission>>,
+
+    pub login_redirect: Option<LoginRedirect>,
+
+    // a route that ends the session
+    pub logout: Option<Logout>,
+
+    // peers whose X-Forwarded-* and Forwarded headers are appended to instead of replaced
+    pub trusted_proxies: Vec<IpNet>,
+
+    pub retry_budget: RetryBudget,
+
+    // named pools routes can point at, everything else goes to `sidecar_url`
+    pub upstreams: HashMap<String, UpstreamPool>,
+
+    pub circuit_breakers: CircuitBreakers,
+
+    // applies to `sidecar_url` and every pool in `upstreams`
+    pub admission: Option<Admission>,
+
+    // applies to `sidecar_url` and every pool in `upstreams`
+    pub adaptive_concurrency: Option<AdaptiveConcurrency>,
+
+    // serves `/metrics`, kept off the public listener
+    pub admin_listening_address: Option<String>,
+}
+
+impl FileConfig {
+    pub fn from_file(path: &str) -> Result<FileConfig> {
+        let text = std::fs::read_to_string(path)?;
+        Ok(serde_yaml::from_str(&text)?)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMode {
+    // a valid session is needed to reach the sidecar
+    #[default]
+    Required,
+    // forwarded with the session permissions when tokens are present, as anonymous otherwise
+    Optional,
+    // forwarded as anonymous without looking at the tokens
+    Public,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Route {
+    // exact path, or a prefix when it ends with `*` (e.g. `/static/*`)
+    pub path: String,
+
+    // empty means every method
+    #[serde(default)]
+    pub methods: Vec<String>,
+
+    #[serde(default)]
+    pub auth: AuthMode,
+
+    #[serde(default)]
+    pub timeouts: Timeouts,
+
+    pub retry: Option<Retry>,
+
+    // name of a pool in `upstreams`
+    pub upstream: Option<String>,
+
+    pub rate_limit: Option<RateLimit>,
+
+    // the user must hold all of them, 403 otherwise
+    #[serde(default)]
+    pub required_permissions: Vec<RequiredPermission>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Timeouts {
+    pub connect_ms: Option<u64>,
+    // until the response headers are received
+    pub first_byte_ms: Option<u64>,
+    // the whole exchange, retries and response body included
+    pub total_ms: Option<u64>,
+}
+
+/// Retries are only done for idempotent methods.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Retry {
+    // attempts after the first one
+    pub attempts: u32,
+    // connect errors are always retried, these statuses are retried as well
+    pub statuses: Vec<u16>,
+    pub backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            attempts: 2,
+            statuses: vec![502, 503],
+            backoff_ms: 50,
+            max_backoff_ms: 1000,
+        }
+    }
+}
+
+/// Caps retries to a share of the requests so retries can not multiply load on a struggling upstream.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RetryBudget {
+    pub ratio: f64,
+    pub min_retries_per_second: u64,
+}
+
+impl Default for RetryBudget {
+    fn default() -> Self {
+        RetryBudget {
+            ratio: 0.2,
+            min_retries_per_second: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LoginRedirect {
+    // where browsers without valid tokens are sent
+    pub url: String,
+
+    #[serde(default = "default_return_to_param")]
+    pub return_to_param: String,
+
+    // origins (e.g. `https://app.example.com`) that `return_to` may point at
+    #[serde(default)]
+    pub allowed_return_to: Vec<String>,
+}
+
+fn default_return_to_param() -> String {
+    String::from("return_to")
+}
+
+/// A `POST` to `path` ends the session here and on peers, closes its websockets and clears the
+/// auth cookies. `upstream_url` is then called with the request's cookies when set.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Logout {
+    #[serde(default = "default_logout_path")]
+    pub path: String,
+
+    pub upstream_url: Option<String>,
+
+    // where the browser is sent afterwards, `204 No Content` otherwise
+    pub redirect_to: Option<String>,
+
+    // the cookies are cleared for this path and domain, they must match where they were set
+    #[serde(default = "default_cookie_path")]
+    pub cookie_path: String,
+    pub cookie_domain: Option<String>,
+}
+
+fn default_logout_path() -> String {
+    String::from("/logout")
+}
+
+fn default_cookie_path() -> String {
+    String::from("/")
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    #[default]
+    RoundRobin,
+    LeastConnections,
+    // by the user's `sub`, keeps a user's websockets on the same endpoint
+    ConsistentHash,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct UpstreamPool {
+    pub endpoints: Vec<String>,
+
+    #[serde(default)]
+    pub strategy: Strategy,
+
+    pub health_check: Option<HealthCheck>,
+
+    #[serde(default)]
+    pub passive_ejection: PassiveEjection,
+
+    // recovered endpoints ramp up to their full share of traffic over this time
+    #[serde(default)]
+    pub slow_start_ms: u64,
+
+    // overrides `circuit_breakers.upstreams` for this pool
+    pub circuit_breaker: Option<CircuitBreaker>,
+
+    // overrides `admission` for this pool
+    pub admission: Option<Admission>,
+
+    // overrides `adaptive_concurrency` for this pool
+    pub adaptive_concurrency: Option<AdaptiveConcurrency>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HealthCheck {
+    pub path: String,
+    pub interval_ms: u64,
+    pub timeout_ms: u64,
+    pub unhealthy_threshold: u32,
+    pub healthy_threshold: u32,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        HealthCheck {
+            path: String::from("/healthz"),
+            interval_ms: 5000,
+            timeout_ms: 1000,
+            unhealthy_threshold: 2,
+            healthy_threshold: 1,
+        }
+    }
+}
+
+/// Endpoints failing `consecutive_failures` requests in a row are taken out for `ejection_ms`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PassiveEjection {
+    pub consecutive_failures: u32,
+    pub ejection_ms: u64,
+}
+
+impl Default for PassiveEjection {
+    fn default() -> Self {
+        PassiveEjection {
+            consecutive_failures: 5,
+            ejection_ms: 30000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct CircuitBreakers {
+    pub permission_service: Option<CircuitBreaker>,
+    // applies to `sidecar_url` and every pool in `upstreams`
+    pub upstreams: Option<CircuitBreaker>,
+}
+
+/// Opens after `failure_threshold` failures in a row and fails fast for `open_ms`, then lets
+/// `half_open_max_requests` probes through and closes after `success_threshold` of them succeed.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CircuitBreaker {
+    pub failure_threshold: u32,
+    pub open_ms: u64,
+    pub half_open_max_requests: u32,
+    pub success_threshold: u32,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        CircuitBreaker {
+            failure_threshold: 5,
+            open_ms: 10000,
+            half_open_max_requests: 1,
+            success_threshold: 1,
+        }
+    }
+}
+
+/// Token bucket refilling `rate` tokens a second up to `burst`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Limit {
+    pub rate: f64,
+    pub burst: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Tier {
+    pub permission: RequiredPermission,
+    #[serde(flatten)]
+    pub limit: Limit,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RateLimit {
+    // per user, when none of the tiers apply
+    pub default: Option<Limit>,
+    // per client ip, for requests without a session
+    pub anonymous: Option<Limit>,
+    // checked in order, the first one whose permission the user holds is used
+    pub tiers: Vec<Tier>,
+    // per user, messages sent by the client over websockets
+    pub websocket_messages: Option<Limit>,
+}
+
+/// At most `max_concurrency` requests in flight to an upstream, up to `max_queue` more wait
+/// for `queue_timeout_ms`. Users holding the first of `priorities` are served first, users
+/// holding none of them last and they are the first to be shed.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Admission {
+    pub max_concurrency: usize,
+    pub max_queue: usize,
+    pub queue_timeout_ms: u64,
+    pub priorities: Vec<RequiredPermission>,
+}
+
+impl Default for Admission {
+    fn default() -> Self {
+        Admission {
+            max_concurrency: 100,
+            max_queue: 100,
+            queue_timeout_ms: 1000,
+            priorities: Vec::new(),
+        }
+    }
+}
+
+/// Starts at `initial_limit` requests in flight and adds one for every fast response while the
+/// limit is in use. Failures and responses slower than `latency_tolerance` times the average
+/// multiply it by `backoff_ratio`, never going outside `min_limit` and `max_limit`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AdaptiveConcurrency {
+    pub initial_limit: usize,
+    pub min_limit: usize,
+    pub max_limit: usize,
+    pub latency_tolerance: f64,
+    pub backoff_ratio: f64,
+}
+
+impl Default for AdaptiveConcurrency {
+    fn default() -> Self {
+        AdaptiveConcurrency {
+            initial_limit: 20,
+            min_limit: 1,
+            max_limit: 1000,
+            latency_tolerance: 2.0,
+            backoff_ratio: 0.9,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PermissionProvider {
+    // the permission service, at `$PERMISSION_URL` unless `url` is set
+    Http(Box<PermissionService>),
+    // YAML file with `subjects` and `issuers` mapped to permissions
+    Static {
+        path: String,
+    },
+    // a claim of the access token
+    JwtClaim {
+        claim: String,
+    },
+    Chain {
+        #[serde(default)]
+        mode: ChainMode,
+        providers: Vec<PermissionProvider>,
+    },
+}
+
+impl Default for PermissionProvider {
+    fn default() -> Self {
+        PermissionProvider::Http(Box::default())
+    }
+}
+
+/// How the permission service is called. `{sub}` and `{iss}` in `url` and in the strings of
+/// `body` are replaced with the token's claims, and a `body` string that is exactly `{filter}`
+/// with the permissions the gateway cares about.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PermissionService {
+    pub url: Option<String>,
+    pub method: String,
+    pub auth: PermissionServiceAuth,
+    pub headers: HashMap<String, String>,
+    // sent as JSON
+    pub body: Option<serde_json::Value>,
+    // asks only for the permissions referenced by the routes, rate limits and admission
+    pub filter: bool,
+    pub response: PermissionResponse,
+    pub client: PermissionClient,
+}
+
+impl Default for PermissionService {
+    fn default() -> Self {
+        PermissionService {
+            url: None,
+            method: String::from("GET"),
+            auth: PermissionServiceAuth::default(),
+            headers: HashMap::new(),
+            body: None,
+            filter: false,
+            response: PermissionResponse::default(),
+            client: PermissionClient::default(),
+        }
+    }
+}
+
+/// The connection pool and timeouts of the client calling the permission service. Connection
+/// errors, timeouts and 502, 503 and 504 answers are retried up to `retries` times.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PermissionClient {
+    pub connect_timeout_ms: u64,
+    // each attempt, the answer's body included
+    pub timeout_ms: u64,
+    pub pool_max_idle_per_host: usize,
+    pub pool_idle_timeout_ms: u64,
+    pub retries: u32,
+    pub backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for PermissionClient {
+    fn default() -> Self {
+        PermissionClient {
+            connect_timeout_ms: 1000,
+            timeout_ms: 5000,
+            pool_max_idle_per_host: 32,
+            pool_idle_timeout_ms: 90000,
+            retries: 2,
+            backoff_ms: 50,
+            max_backoff_ms: 1000,
+        }
+    }
+}
+
+/// Where the permissions are in the permission service's answer. A JSON list holds permission
+/// names or objects with a `name`, an optional `expires_at` in unix seconds and `metadata`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PermissionResponse {
+    pub format: ResponseFormat,
+    // JSON pointer to the list, e.g. `/data/permissions`, the whole body by default
+    pub pointer: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseFormat {
+    #[default]
+    Json,
+    // one permission per line
+    Text,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PermissionServiceAuth {
+    // the user's tokens in the configured cookies
+    #[default]
+    Cookie,
+    // the user's access token as `Authorization: Bearer`
+    Bearer,
+    // the gateway's own credentials, read from the environment variable `env`
+    Service {
+        #[serde(default = "default_service_header")]
+        header: String,
+        env: String,
+    },
+}
+
+fn default_service_header() -> String {
+    String::from("authorization")
+}
+
+/// Permissions are kept per `sub` for `ttl_ms` unless the provider says otherwise, refreshed in
+/// the background `refresh_ahead_ms` before they expire, and still used for `stale_if_error_ms`
+/// after expiring while the provider fails.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PermissionCache {
+    pub ttl_ms: u64,
+    pub refresh_ahead_ms: u64,
+    pub stale_if_error_ms: u64,
+    pub max_entries: usize,
+}
+
+impl Default for PermissionCache {
+    fn default() -> Self {
+        PermissionCache {
+            ttl_ms: 60000,
+            refresh_ahead_ms: 10000,
+            stale_if_error_ms: 300000,
+            max_entries: 100000,
+        }
+    }
+}
+
+/// The permission service calls `path` with the new permissions of a user. Events are signed
+/// with the secret in `secret_env` and refused once older than `max_age_ms`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PermissionWebhook {
+    #[serde(default = "default_webhook_path")]
+    pub path: String,
+    // where the permission service reaches `path`, registered with it on startup
+    pub public_url: String,
+    pub secret_env: String,
+    // next to `$PERMISSION_URL` by default
+    pub register_url: Option<String>,
+    pub unsubscribe_url: Option<String>,
+    #[serde(default = "default_webhook_max_age_ms")]
+    pub max_age_ms: u64,
+}
+
+fn default_webhook_path() -> String {
+    String::from("/permission_update_hook")
+}
+
+fn default_webhook_max_age_ms() -> u64 {
+    300000
+}
+
+/// Every `sweep_interval_ms` sessions that saw no request and have no open websocket for
+/// `idle_timeout_ms` are dropped. Past `max_sessions` the least recently used sessions are dropped
+/// right away.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Sessions {
+    pub idle_timeout_ms: u64,
+    pub sweep_interval_ms: u64,
+    pub max_sessions: usize,
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Sessions {
+            idle_timeout_ms: 1800000,
+            sweep_interval_ms: 60000,
+            max_sessions: 100000,
+        }
+    }
+}
+
+/// Sessions are written to `path` every `save_interval_ms` and on shutdown, and loaded again on
+/// startup. The file is encrypted with the key in `key_env`, 32 bytes as hex.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SessionSnapshot {
+    pub path: String,
+    pub key_env: String,
+    #[serde(default = "default_snapshot_save_interval_ms")]
+    pub save_interval_ms: u64,
+}
+
+fn default_snapshot_save_interval_ms() -> u64 {
+    60000
+}
+
+/// Replicas send each other their session changes so any of them can take any user's websocket.
+/// Each listens for the others on `listening_address`, an internal address, and reaches them at
+/// `urls`. Events are signed with the secret in `secret_env` like permission webhook events and
+/// refused once older than `max_age_ms`. Every replica needs the same `$SOCKET_ENCRYPTION_KEY`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Peers {
+    pub listening_address: String,
+    pub urls: Vec<String>,
+    pub secret_env: String,
+    #[serde(default = "default_peer_max_age_ms")]
+    pub max_age_ms: u64,
+}
+
+fn default_peer_max_age_ms() -> u64 {
+    30000
+}
+
+/// When looking up a user's permissions fails, the permissions last fetched for them are used for
+/// up to `grace_ms` after that fetch, only those granted by `safe_permissions` when it is set.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DegradedMode {
+    pub grace_ms: u64,
+    pub safe_permissions: Option<Vec<Permission>>,
+}
+
+impl Default for DegradedMode {
+    fn default() -> Self {
+        DegradedMode {
+            grace_ms: 300000,
+            safe_permissions: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChainMode {
+    // the first provider that answers
+    #[default]
+    First,
+    // the permissions of every provider that answers
+    Merge,
 }
@@ -0,0 +1,264 @@
+use std::net::{IpAddr, SocketAddr};
+
+use hyper::{
+    header::{self, HeaderName, HeaderValue},
+    HeaderMap, Uri,
+};
+use ipnet::IpNet;
+
+use crate::utils;
+
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+// JSON object of the metadata the permission provider returned for the user
+pub const X_PERMISSION_METADATA: HeaderName = HeaderName::from_static("x-permission-metadata");
+// set when the permissions are the last known ones because the provider is failing
+pub const X_PERMISSIONS_DEGRADED: HeaderName = HeaderName::from_static("x-permissions-degraded");
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+// headers that only make sense for a single connection and must not be proxied
+const HOP_BY_HOP_HEADERS: [HeaderName; 8] = [
+    header::CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    header::PROXY_AUTHENTICATE,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+fn is_trusted(ip: &IpAddr, trusted_proxies: &[IpNet]) -> bool {
+    trusted_proxies.iter().any(|net| net.contains(ip))
+}
+
+fn joined_header(headers: &HeaderMap, name: &HeaderName) -> Option<String> {
+    let values = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<&str>>();
+    (!values.is_empty()).then(|| values.join(", "))
+}
+
+fn forwarded_node(ip: &IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+    }
+}
+
+/// The address of the client, taken from `X-Forwarded-For` only as far as the chain of
+/// trusted proxies goes.
+pub fn client_ip(
+    headers: &HeaderMap,
+    remote_addr: &SocketAddr,
+    trusted_proxies: &[IpNet],
+) -> IpAddr {
+    let mut ip = remote_addr.ip();
+    if !is_trusted(&ip, trusted_proxies) {
+        return ip;
+    }
+
+    let chain = joined_header(headers, &X_FORWARDED_FOR).unwrap_or_default();
+    for hop in chain.rsplit(',') {
+        match hop.trim().parse::<IpAddr>() {
+            Ok(hop) => {
+                ip = hop;
+                if !is_trusted(&ip, trusted_proxies) {
+                    break;
+                }
+            }
+            Err(_) => break,
+        }
+    }
+    ip
+}
+
+/// The path and query sent upstream, with the comma separated permissions the gateway vouches for.
+pub fn upstream_path_and_query(uri: &Uri, permissions: &str) -> String {
+    // never let the client supply its own permissions, and keep the socket key to the gateway
+    let mut query = uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|param| {
+            !param.is_empty()
+                && !param.starts_with("permissions=")
+                && !param.starts_with("websocket_key=")
+        })
+        .collect::<Vec<&str>>()
+        .join("&");
+    if !query.is_empty() {
+        query.push('&');
+    }
+    query.push_str("permissions=");
+    query.push_str(permissions);
+    format!("{}?{}", uri.path(), query)
+}
+
+/// Removes the hop-by-hop headers, including the ones named by the `Connection` header.
+pub fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+    let named = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect::<Vec<HeaderName>>();
+
+    for name in named.iter().chain(HOP_BY_HOP_HEADERS.iter()) {
+        headers.remove(name);
+    }
+}
+
+/// Reuses a sane incoming `X-Request-Id`, otherwise generates a new one.
+pub fn get_or_generate_request_id(headers: &HeaderMap) -> HeaderValue {
+    headers
+        .get(X_REQUEST_ID)
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= 128
+                && id
+                    .as_bytes()
+                    .iter()
+                    .all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(b))
+        })
+        .cloned()
+        .unwrap_or_else(|| {
+            HeaderValue::from_str(&utils::generate_uuid()).expect("uuid is a valid header value")
+        })
+}
+
+/// Sets `X-Forwarded-*` and `Forwarded` for the upstream. The existing values are only kept
+/// when the peer is a trusted proxy, otherwise the client could spoof its address.
+pub fn set_forwarding_headers(
+    headers: &mut HeaderMap,
+    remote_addr: &SocketAddr,
+    trusted_proxies: &[IpNet],
+) {
+    let ip = remote_addr.ip();
+    let trusted = is_trusted(&ip, trusted_proxies);
+
+    let host = headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .map(String::from);
+
+    let previous = |headers: &HeaderMap, name: &HeaderName| {
+        if trusted {
+            joined_header(headers, name)
+        } else {
+            None
+        }
+    };
+
+    let forwarded_for = match previous(headers, &X_FORWARDED_FOR) {
+        Some(chain) => format!("{}, {}", chain, ip),
+        None => ip.to_string(),
+    };
+    let proto = previous(headers, &X_FORWARDED_PROTO).unwrap_or_else(|| String::from("http"));
+    let forwarded_host = previous(headers, &X_FORWARDED_HOST).or_else(|| host.clone());
+
+    let mut forwarded = format!("for={};proto={}", forwarded_node(&ip), proto);
+    if let Some(host) = &host {
+        forwarded.push_str(&format!(";host=\"{}\"", host));
+    }
+    if let Some(chain) = previous(headers, &header::FORWARDED) {
+        forwarded = format!("{}, {}", chain, forwarded);
+    }
+
+    set_header(headers, X_FORWARDED_FOR, Some(&forwarded_for));
+    set_header(headers, X_FORWARDED_PROTO, Some(&proto));
+    set_header(headers, X_FORWARDED_HOST, forwarded_host.as_deref());
+    set_header(headers, header::FORWARDED, Some(&forwarded));
+}
+
+// values that are not valid header values (e.g. a weird host) are dropped
+fn set_header(headers: &mut HeaderMap, name: HeaderName, value: Option<&str>) {
+    match value.and_then(|value| HeaderValue::from_str(value).ok()) {
+        Some(value) => {
+            headers.insert(name, value);
+        }
+        None => {
+            headers.remove(name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_static("app.example.com"));
+        headers.insert(X_FORWARDED_FOR, HeaderValue::from_static("1.1.1.1"));
+        headers.insert(
+            header::CONNECTION,
+            HeaderValue::from_static("keep-alive, x-debug"),
+        );
+        headers.insert("x-debug", HeaderValue::from_static("1"));
+        headers
+    }
+
+    #[test]
+    fn test_set_forwarding_headers() {
+        let remote_addr: SocketAddr = "10.0.0.2:4000".parse().unwrap();
+        let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+
+        let mut trusted_headers = headers();
+        set_forwarding_headers(&mut trusted_headers, &remote_addr, &trusted);
+        assert_eq!(trusted_headers[X_FORWARDED_FOR], "1.1.1.1, 10.0.0.2");
+        assert_eq!(trusted_headers[X_FORWARDED_HOST], "app.example.com");
+        assert_eq!(
+            trusted_headers[header::FORWARDED],
+            "for=10.0.0.2;proto=http;host=\"app.example.com\""
+        );
+
+        let mut untrusted_headers = headers();
+        set_forwarding_headers(&mut untrusted_headers, &remote_addr, &[]);
+        assert_eq!(untrusted_headers[X_FORWARDED_FOR], "10.0.0.2");
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let remote_addr: SocketAddr = "10.0.0.2:4000".parse().unwrap();
+        let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            X_FORWARDED_FOR,
+            HeaderValue::from_static("6.6.6.6, 1.1.1.1, 10.0.0.1"),
+        );
+
+        assert_eq!(
+            client_ip(&headers, &remote_addr, &trusted).to_string(),
+            "1.1.1.1"
+        );
+        assert_eq!(
+            client_ip(&headers, &remote_addr, &[]).to_string(),
+            "10.0.0.2"
+        );
+    }
+
+    #[test]
+    fn test_remove_hop_by_hop_headers() {
+        let mut headers = headers();
+        remove_hop_by_hop_headers(&mut headers);
+        assert!(headers.get(header::CONNECTION).is_none());
+        assert!(headers.get("x-debug").is_none());
+        assert!(headers.get(header::HOST).is_some());
+    }
+
+    #[test]
+    fn test_upstream_path_and_query() {
+        let uri: Uri = "/quotes?permissions=all&symbol=AAPL&websocket_key=k.h"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            upstream_path_and_query(&uri, "cta,nasdaq"),
+            "/quotes?symbol=AAPL&permissions=cta,nasdaq"
+        );
+    }
+}
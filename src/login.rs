@@ -0,0 +1,111 @@
+use http_body_util::Full;
+use hyper::{body::Bytes, header, Method, Request, Response, StatusCode};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+
+use crate::config::LoginRedirect;
+
+fn is_html_navigation<B>(req: &Request<B>) -> bool {
+    (req.method() == Method::GET || req.method() == Method::HEAD)
+        && !hyper_tungstenite::is_upgrade_request(req)
+        && req
+            .headers()
+            .get_all(header::ACCEPT)
+            .iter()
+            .any(|accept| accept.to_str().unwrap_or("").contains("text/html"))
+}
+
+// the host header is user controlled, only origins from the allowlist are ever used
+fn get_return_to<B>(req: &Request<B>, login: &LoginRedirect) -> Option<String> {
+    let path_and_query = req
+        .uri()
+        .path_and_query()
+        .map_or("/", |path_and_query| path_and_query.as_str());
+
+    let host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| req.uri().authority().map(|authority| authority.as_str()))?;
+
+    login
+        .allowed_return_to
+        .iter()
+        .map(|origin| origin.trim_end_matches('/'))
+        .find(|origin| {
+            origin
+                .split_once("://")
+                .is_some_and(|(_, authority)| authority.eq_ignore_ascii_case(host))
+        })
+        .map(|origin| format!("{}{}", origin, path_and_query))
+}
+
+/// Sends browsers without valid tokens to the login page, every other request gets `None`
+/// and should be answered with a 401.
+pub fn redirect_response<B>(
+    req: &Request<B>,
+    login: &LoginRedirect,
+) -> Option<Response<Full<Bytes>>> {
+    if !is_html_navigation(req) {
+        return None;
+    }
+
+    let mut location = login.url.clone();
+    if let Some(return_to) = get_return_to(req, login) {
+        location.push(if location.contains('?') { '&' } else { '?' });
+        location.push_str(&format!(
+            "{}={}",
+            login.return_to_param,
+            utf8_percent_encode(&return_to, NON_ALPHANUMERIC)
+        ));
+    }
+
+    Response::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, location)
+        .body(Full::new(Bytes::new()))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn login() -> LoginRedirect {
+        LoginRedirect {
+            url: String::from("https://auth.example.com/login"),
+            return_to_param: String::from("return_to"),
+            allowed_return_to: vec![String::from("https://app.example.com")],
+        }
+    }
+
+    fn request(host: &str, path: &str, accept: &str) -> Request<()> {
+        Request::builder()
+            .uri(path)
+            .header(header::HOST, host)
+            .header(header::ACCEPT, accept)
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_redirect_response() {
+        let req = request("app.example.com", "/news?id=1", "text/html,*/*");
+        let response = redirect_response(&req, &login()).unwrap();
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(
+            response.headers()[header::LOCATION],
+            "https://auth.example.com/login?return_to=https%3A%2F%2Fapp%2Eexample%2Ecom%2Fnews%3Fid%3D1"
+        );
+
+        // hosts outside the allowlist never end up in return_to
+        let req = request("evil.com", "/news", "text/html");
+        let response = redirect_response(&req, &login()).unwrap();
+        assert_eq!(
+            response.headers()[header::LOCATION],
+            "https://auth.example.com/login"
+        );
+
+        let req = request("app.example.com", "/api/news", "application/json");
+        assert!(redirect_response(&req, &login()).is_none());
+    }
+}
@@ -0,0 +1,139 @@
+use anyhow::Result;
+use http_body_util::Full;
+use hyper::{body::Bytes, header, Method, Request, Response, StatusCode};
+use std::time::Duration;
+
+use crate::{config, peers, sessions::Eviction, state::State, sweeper, utils};
+
+/// Ends sessions on `POST` to `config::Logout::path`.
+pub struct Logout {
+    config: config::Logout,
+    client: reqwest::Client,
+    upstream_url: Option<reqwest::Url>,
+    access_token_jwt_cookie_name: String,
+    refresh_token_jwt_cookie_name: String,
+}
+
+// expires the cookie, the path and domain must be the ones it was set with
+fn clear_cookie(name: &str, logout: &config::Logout) -> String {
+    let mut cookie = format!(
+        "{}=; Path={}; Max-Age=0; Expires=Thu, 01 Jan 1970 00:00:00 GMT",
+        name, logout.cookie_path
+    );
+    if let Some(domain) = &logout.cookie_domain {
+        cookie.push_str(&format!("; Domain={}", domain));
+    }
+    cookie
+}
+
+impl Logout {
+    pub fn new(config: &config::Config) -> Result<Option<Self>> {
+        let Some(logout) = &config.logout else {
+            return Ok(None);
+        };
+
+        Ok(Some(Logout {
+            config: logout.clone(),
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(5))
+                .build()?,
+            upstream_url: logout
+                .upstream_url
+                .as_deref()
+                .map(reqwest::Url::parse)
+                .transpose()?,
+            access_token_jwt_cookie_name: config.access_token_jwt_cookie_name.clone(),
+            refresh_token_jwt_cookie_name: config.refresh_token_jwt_cookie_name.clone(),
+        }))
+    }
+
+    pub fn matches(&self, method: &Method, path: &str) -> bool {
+        method == Method::POST && path == self.config.path
+    }
+
+    // lets the identity provider end its own session, the local one is gone either way
+    async fn call_upstream(&self, url: &reqwest::Url, cookies: Option<&header::HeaderValue>) {
+        let mut request = self.client.post(url.clone());
+        if let Some(cookies) = cookies.and_then(|cookies| cookies.to_str().ok()) {
+            request = request.header(header::COOKIE.as_str(), cookies);
+        }
+        match request.send().await {
+            Ok(response) if response.status().is_success() => (),
+            Ok(response) => eprintln!("Upstream logout answered {}", response.status()),
+            Err(err) => eprintln!("Error calling upstream logout: {}", err),
+        }
+    }
+
+    /// Drops the session, closes its websockets here and on the peers and clears the cookies.
+    /// Answers the same when there is no session, the browser may hold stale cookies.
+    pub async fn handle(
+        &self,
+        req: Request<hyper::body::Incoming>,
+        state: &State,
+    ) -> Result<Response<Full<Bytes>>> {
+        // expired tokens still name a session to end, so the cookie is not parsed as a token
+        let refresh_token = utils::get_cookies(&req)
+            .filter_map(|cookie| cookie.strip_prefix(self.refresh_token_jwt_cookie_name.as_str()))
+            .find_map(|value| value.strip_prefix('='))
+            .filter(|token| !token.is_empty())
+            .map(str::to_string);
+
+        if let Some(refresh_token) = refresh_token {
+            let ended = state.sessions.logout(&refresh_token);
+            if ended.is_empty() {
+                // the session may only be held by the peers
+                peers::publish_end(state, &refresh_token, Eviction::Logout.message());
+            }
+            sweeper::end_sessions(state, ended).await;
+        }
+
+        if let Some(url) = &self.upstream_url {
+            self.call_upstream(url, req.headers().get(header::COOKIE))
+                .await;
+        }
+
+        let mut response = match &self.config.redirect_to {
+            Some(location) => {
+                let mut response = utils::status_response(StatusCode::SEE_OTHER, "");
+                response
+                    .headers_mut()
+                    .insert(header::LOCATION, location.parse()?);
+                response
+            }
+            None => utils::status_response(StatusCode::NO_CONTENT, ""),
+        };
+        for name in [
+            &self.access_token_jwt_cookie_name,
+            &self.refresh_token_jwt_cookie_name,
+        ] {
+            response.headers_mut().append(
+                header::SET_COOKIE,
+                clear_cookie(name, &self.config).parse()?,
+            );
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clear_cookie() {
+        let mut logout = config::Logout {
+            path: String::from("/logout"),
+            upstream_url: None,
+            redirect_to: None,
+            cookie_path: String::from("/"),
+            cookie_domain: None,
+        };
+        assert_eq!(
+            clear_cookie("refresh_token", &logout),
+            "refresh_token=; Path=/; Max-Age=0; Expires=Thu, 01 Jan 1970 00:00:00 GMT"
+        );
+
+        logout.cookie_domain = Some(String::from(".example.com"));
+        assert!(clear_cookie("refresh_token", &logout).ends_with("; Domain=.example.com"));
+    }
+}
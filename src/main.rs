@@ -1,29 +1,60 @@
 use anyhow::Result;
-use http_body_util::Full;
-use hyper::body::Bytes;
 use hyper_util::rt::TokioIo;
 use std::{env, sync::Arc};
 
+mod admin;
+mod admission;
+mod circuit_breaker;
+mod concurrency_limit;
 mod config;
 mod error;
+mod forwarding;
 mod jwt;
+mod login;
+mod logout;
+mod metrics;
+mod peers;
+mod permission;
+mod pool;
+mod rate_limit;
 mod request;
+mod routes;
+mod scheduler;
 mod session;
+mod session_store;
 mod sessions;
 mod socket;
-mod user;
+mod state;
+mod sweeper;
+mod upstream;
 mod utils;
+mod webhook;
 
 use crate::error::Error;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
+    let file_config = match env::var("CONFIG_FILE") {
+        Ok(path) => config::FileConfig::from_file(&path)?,
+        Err(_) => config::FileConfig::default(),
+    };
+
     let config: Arc<config::Config> = Arc::new(config::Config {
         listening_address: env::var("LISTENING_ADDRESS").expect("$LISTENING_ADDRESS is not set"),
 
         permission_url: env::var("PERMISSION_URL")
-            .expect("$PERMISSION_URL is not set")
-            .parse()?,
+            .ok()
+            .map(|url| url.parse())
+            .transpose()?,
+
+        permission_provider: file_config.permission_provider,
+        insecure_unverified_tokens: file_config.insecure_unverified_tokens,
+        permission_cache: file_config.permission_cache,
+        permission_webhook: file_config.permission_webhook,
+        degraded_mode: file_config.degraded_mode,
+        sessions: file_config.sessions,
+        session_snapshot: file_config.session_snapshot,
+        peers: file_config.peers,
 
         socket_encryption_key: env::var("SOCKET_ENCRYPTION_KEY")
             .expect("$SOCKET_ENCRYPTION_KEY is not set"),
@@ -37,10 +68,26 @@ async fn main() -> Result<(), Error> {
 
         refresh_token_jwt_cookie_name: env::var("REFRESH_TOKEN_JWT_COOKIE_NAME")
             .expect("$REFRESH_TOKEN_JWT_COOKIE_NAME is not set"),
+
+        routes: file_config.routes,
+        anonymous_permissions: permission::expand_roles(
+            &file_config.roles,
+            file_config.anonymous_permissions,
+        ),
+        roles: file_config.roles,
+        login_redirect: file_config.login_redirect,
+        logout: file_config.logout,
+        trusted_proxies: file_config.trusted_proxies,
+        retry_budget: file_config.retry_budget,
+        upstreams: file_config.upstreams,
+        circuit_breakers: file_config.circuit_breakers,
+        admission: file_config.admission,
+        adaptive_concurrency: file_config.adaptive_concurrency,
+        admin_listening_address: file_config.admin_listening_address,
     });
 
     // This will store the keys and their states
-    let active_sessions = Arc::new(sessions::SafeSessions::new());
+    let active_sessions = Arc::new(sessions::SafeSessions::new(config.sessions.clone()));
 
     let addr: std::net::SocketAddr = config.listening_address.parse()?;
     let listener = tokio::net::TcpListener::bind(&addr).await?;
@@ -49,21 +96,72 @@ async fn main() -> Result<(), Error> {
     let mut http = hyper::server::conn::http1::Builder::new();
     http.keep_alive(true);
 
-    // Create the Hyper client
-    let client: hyper_util::client::legacy::Client<_, Full<Bytes>> =
-        hyper_util::client::legacy::Client::builder(hyper_util::rt::TokioExecutor::new())
-            .build_http();
+    // Create the Hyper clients
+    let upstream = Arc::new(upstream::Upstream::new(&config)?);
+    upstream.start_health_checks();
+
+    let permission_breaker = config
+        .circuit_breakers
+        .permission_service
+        .clone()
+        .map(|breaker| {
+            Arc::new(circuit_breaker::CircuitBreaker::new(
+                "permission service",
+                breaker,
+            ))
+        });
+
+    let state = Arc::new(state::State {
+        config: config.clone(),
+        sessions: active_sessions,
+        upstream,
+        permission_provider: permission::build(&config, permission_breaker.clone())?,
+        permission_breaker,
+        rate_limiter: rate_limit::RateLimiter::new(),
+        webhook: webhook::Webhook::new(&config)?,
+        logout: logout::Logout::new(&config)?,
+        scheduler: scheduler::Scheduler::new(),
+        session_store: session_store::build(&config)?,
+        peers: peers::Peers::new(&config)?,
+        anonymous_permissions: permission::PermissionSet::new(
+            config.anonymous_permissions.iter().map(String::as_str),
+        ),
+        permission_fallbacks: Default::default(),
+        degraded_requests: Default::default(),
+    });
+
+    if let Some(webhook) = &state.webhook {
+        webhook.register();
+    }
+    match session_store::restore(&state).await {
+        Ok(0) => (),
+        Ok(restored) => println!("Restored {} sessions", restored),
+        Err(err) => eprintln!("Error restoring sessions: {:?}", err),
+    }
+    scheduler::start(state.clone());
+    sweeper::start(state.clone());
+    let saving = session_store::start(state.clone());
+
+    if let Some(address) = &config.admin_listening_address {
+        admin::serve(address, state.clone()).await?;
+    }
+    if let Some(peers) = &config.peers {
+        peers::serve(&peers.listening_address, state.clone()).await?;
+    }
 
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
     loop {
-        let (stream, _) = listener.accept().await?;
-        let keys = active_sessions.clone(); // Clone `keys` before moving it into the closure
-        let config = config.clone();
-        let client = client.clone();
+        let (stream, remote_addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = &mut shutdown => break,
+        };
+        let state = state.clone(); // Clone `state` before moving it into the closure
         let connection = http
             .serve_connection(
                 TokioIo::new(stream),
                 hyper::service::service_fn(move |req| {
-                    request::handle_request(req, keys.clone(), config.clone(), client.clone())
+                    request::handle_request(req, remote_addr, state.clone())
                 }),
             )
             .with_upgrades();
@@ -73,4 +171,37 @@ async fn main() -> Result<(), Error> {
             }
         });
     }
+
+    // a periodic save running now would race the last one
+    if let Some(saving) = saving {
+        saving.abort();
+        let _ = saving.await;
+    }
+    if state.session_store.is_some() {
+        match session_store::save(&state).await {
+            Ok(saved) => println!("Saved {} sessions", saved),
+            Err(err) => eprintln!("Error saving sessions: {:?}", err),
+        }
+    }
+    Ok(())
+}
+
+// Ctrl-C, or SIGTERM from e.g. `docker stop`
+async fn shutdown_signal() {
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(err) => {
+                eprintln!("Error listening for SIGTERM: {:?}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = terminate => (),
+    }
+    println!("Shutting down");
 }
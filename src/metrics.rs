@@ -0,0 +1,234 @@
+use std::{fmt::Write, sync::atomic::Ordering};
+
+use crate::{circuit_breaker::BreakerState, permission, pool::Pool, state::State};
+
+// Prometheus text exposition format
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(String, f64)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(out, "{} {}", name, value);
+        } else {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+}
+
+fn breaker_state_value(state: BreakerState) -> f64 {
+    match state {
+        BreakerState::Closed => 0.0,
+        BreakerState::HalfOpen => 1.0,
+        BreakerState::Open => 2.0,
+    }
+}
+
+pub fn render(state: &State) -> String {
+    let mut out = String::new();
+
+    let breakers = state
+        .permission_breaker
+        .as_deref()
+        .into_iter()
+        .chain(state.upstream.pools().filter_map(|pool| pool.breaker()))
+        .map(|breaker| {
+            (
+                format!("breaker=\"{}\"", breaker.name()),
+                breaker_state_value(breaker.state()),
+            )
+        })
+        .collect::<Vec<(String, f64)>>();
+    metric(
+        &mut out,
+        "gateway_circuit_breaker_state",
+        "gauge",
+        "Circuit breaker state, 0 closed, 1 half open, 2 open",
+        &breakers,
+    );
+
+    let endpoints = state
+        .upstream
+        .pools()
+        .flat_map(|pool| {
+            pool.endpoints().iter().map(move |endpoint| {
+                (
+                    format!("upstream=\"{}\",endpoint=\"{}\"", pool.name(), endpoint.uri),
+                    endpoint,
+                )
+            })
+        })
+        .collect::<Vec<_>>();
+    metric(
+        &mut out,
+        "gateway_upstream_endpoint_healthy",
+        "gauge",
+        "Whether the endpoint is receiving traffic",
+        &endpoints
+            .iter()
+            .map(|(labels, endpoint)| {
+                (
+                    labels.clone(),
+                    f64::from(u8::from(Pool::is_healthy(endpoint))),
+                )
+            })
+            .collect::<Vec<(String, f64)>>(),
+    );
+    metric(
+        &mut out,
+        "gateway_upstream_endpoint_active",
+        "gauge",
+        "In flight requests and open websockets per endpoint",
+        &endpoints
+            .iter()
+            .map(|(labels, endpoint)| (labels.clone(), endpoint.active_connections() as f64))
+            .collect::<Vec<(String, f64)>>(),
+    );
+
+    let admissions = state
+        .upstream
+        .pools()
+        .filter_map(|pool| Some((format!("upstream=\"{}\"", pool.name()), pool.admission()?)))
+        .collect::<Vec<_>>();
+    metric(
+        &mut out,
+        "gateway_admission_in_flight",
+        "gauge",
+        "Requests admitted to the upstream",
+        &admissions
+            .iter()
+            .map(|(labels, admission)| (labels.clone(), admission.in_flight() as f64))
+            .collect::<Vec<(String, f64)>>(),
+    );
+    metric(
+        &mut out,
+        "gateway_admission_queued",
+        "gauge",
+        "Requests waiting for the upstream's concurrency limit",
+        &admissions
+            .iter()
+            .map(|(labels, admission)| (labels.clone(), admission.queued() as f64))
+            .collect::<Vec<(String, f64)>>(),
+    );
+
+    let limits = state
+        .upstream
+        .pools()
+        .filter_map(|pool| {
+            let limit = pool.concurrency_limit()?;
+            Some((format!("upstream=\"{}\"", pool.name()), limit))
+        })
+        .collect::<Vec<_>>();
+    metric(
+        &mut out,
+        "gateway_adaptive_concurrency_limit",
+        "gauge",
+        "Current adaptive concurrency limit of the upstream",
+        &limits
+            .iter()
+            .map(|(labels, limit)| (labels.clone(), limit.limit() as f64))
+            .collect::<Vec<(String, f64)>>(),
+    );
+    metric(
+        &mut out,
+        "gateway_adaptive_concurrency_in_flight",
+        "gauge",
+        "Requests counted against the adaptive concurrency limit",
+        &limits
+            .iter()
+            .map(|(labels, limit)| (labels.clone(), limit.in_flight() as f64))
+            .collect::<Vec<(String, f64)>>(),
+    );
+
+    metric(
+        &mut out,
+        "gateway_permissions_registered",
+        "gauge",
+        "Distinct permissions held by sessions, with an id in the registry",
+        &[(String::new(), permission::registered() as f64)],
+    );
+
+    metric(
+        &mut out,
+        "gateway_rate_limit_buckets",
+        "gauge",
+        "Token buckets kept by the rate limiter",
+        &[(String::new(), state.rate_limiter.len() as f64)],
+    );
+
+    metric(
+        &mut out,
+        "gateway_permission_fallbacks_total",
+        "counter",
+        "Failed permission lookups answered with the last known permissions",
+        &[(
+            String::new(),
+            state.permission_fallbacks.load(Ordering::Relaxed) as f64,
+        )],
+    );
+    metric(
+        &mut out,
+        "gateway_degraded_requests_total",
+        "counter",
+        "Requests forwarded with the last known permissions",
+        &[(
+            String::new(),
+            state.degraded_requests.load(Ordering::Relaxed) as f64,
+        )],
+    );
+
+    metric(
+        &mut out,
+        "gateway_sessions",
+        "gauge",
+        "Sessions currently kept",
+        &[(String::new(), state.sessions.len() as f64)],
+    );
+    metric(
+        &mut out,
+        "gateway_scheduled_deadlines",
+        "gauge",
+        "Session deadlines waiting in the scheduler",
+        &[(String::new(), state.scheduler.len() as f64)],
+    );
+    if let Some(peers) = &state.peers {
+        let events = &peers.events;
+        metric(
+            &mut out,
+            "gateway_peer_events_total",
+            "counter",
+            "Session changes exchanged with peers, by result",
+            &[
+                ("sent", &events.sent),
+                ("received", &events.received),
+                ("failed", &events.failed),
+            ]
+            .map(|(result, counter)| {
+                (
+                    format!("result=\"{}\"", result),
+                    counter.load(Ordering::Relaxed) as f64,
+                )
+            }),
+        );
+    }
+    let evictions = &state.sessions.evictions;
+    metric(
+        &mut out,
+        "gateway_sessions_evicted_total",
+        "counter",
+        "Sessions dropped, by reason",
+        &[
+            ("expired", &evictions.expired),
+            ("idle", &evictions.idle),
+            ("capacity", &evictions.capacity),
+            ("logout", &evictions.logout),
+        ]
+        .map(|(reason, counter)| {
+            (
+                format!("reason=\"{}\"", reason),
+                counter.load(Ordering::Relaxed) as f64,
+            )
+        }),
+    );
+
+    out
+}
@@ -0,0 +1,350 @@
+use anyhow::{anyhow, Result};
+use http_body_util::Full;
+use hyper::{body::Bytes, Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::mpsc;
+
+use crate::{
+    config, scheduler,
+    session::{SharedSession, SocketEvent, StoredSession},
+    state::State,
+    sweeper, utils, webhook,
+};
+
+const SYNC_PATH: &str = "/sync";
+
+// events waiting for a peer that is slow or down, later ones are dropped
+const QUEUE_LEN: usize = 1024;
+
+// one session with its permissions and metadata, anything longer is refused unread
+const MAX_BODY_BYTES: usize = 256 * 1024;
+
+/// A change one replica tells the others about.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PeerEvent {
+    // the session was created or refreshed, its permissions changed or it got a socket key
+    SessionChanged {
+        session: StoredSession,
+    },
+    // the session ended, its websockets close with the reason
+    SessionEnded {
+        refresh_token: String,
+        reason: String,
+    },
+}
+
+/// Events so far, for the metrics.
+#[derive(Debug, Default)]
+pub struct PeerEvents {
+    pub sent: AtomicU64,
+    pub received: AtomicU64,
+    // not delivered, the peer catches up with the next change of the session
+    pub failed: AtomicU64,
+}
+
+/// Keeps the sessions of the replicas in `config::Peers` in sync.
+pub struct Peers {
+    config: config::Peers,
+    secret: Vec<u8>,
+    // one per peer, so each gets the events in the order they happened
+    queues: Vec<(String, mpsc::Sender<Bytes>)>,
+    pub events: Arc<PeerEvents>,
+}
+
+impl Peers {
+    /// Starts a task per peer that delivers its events.
+    pub fn new(config: &config::Config) -> Result<Option<Self>> {
+        let Some(peers) = &config.peers else {
+            return Ok(None);
+        };
+        let secret = std::env::var(&peers.secret_env)
+            .map_err(|_| anyhow!("${} is not set", peers.secret_env))?
+            .into_bytes();
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()?;
+        let events = Arc::new(PeerEvents::default());
+
+        let mut queues = Vec::new();
+        for url in &peers.urls {
+            let (sender, receiver) = mpsc::channel(QUEUE_LEN);
+            tokio::spawn(deliver(
+                client.clone(),
+                reqwest::Url::parse(url)?.join(SYNC_PATH)?,
+                secret.clone(),
+                receiver,
+                events.clone(),
+            ));
+            queues.push((url.clone(), sender));
+        }
+
+        Ok(Some(Peers {
+            config: peers.clone(),
+            secret,
+            queues,
+            events,
+        }))
+    }
+
+    pub fn broadcast(&self, event: &PeerEvent) {
+        let body = match serde_json::to_vec(event) {
+            Ok(body) => Bytes::from(body),
+            Err(err) => {
+                eprintln!("Error encoding peer event: {:?}", err);
+                return;
+            }
+        };
+        for (url, queue) in &self.queues {
+            if queue.try_send(body.clone()).is_err() {
+                self.events.failed.fetch_add(1, Ordering::Relaxed);
+                eprintln!("Dropped an event for peer {}, it is not keeping up", url);
+            }
+        }
+    }
+}
+
+async fn deliver(
+    client: reqwest::Client,
+    url: reqwest::Url,
+    secret: Vec<u8>,
+    mut queue: mpsc::Receiver<Bytes>,
+    events: Arc<PeerEvents>,
+) {
+    while let Some(body) = queue.recv().await {
+        let timestamp = utils::get_current_unix_timestamp();
+        let signature = match webhook::sign(&secret, timestamp, &body) {
+            Ok(signature) => signature,
+            Err(err) => {
+                eprintln!("Error signing peer event: {:?}", err);
+                continue;
+            }
+        };
+        let response = client
+            .post(url.clone())
+            .header(webhook::X_WEBHOOK_TIMESTAMP, timestamp.to_string())
+            .header(webhook::X_WEBHOOK_SIGNATURE, signature)
+            .body(body)
+            .send()
+            .await;
+        match response {
+            Ok(response) if response.status().is_success() => {
+                events.sent.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            Ok(response) => eprintln!("Peer {} answered {}", url, response.status()),
+            Err(err) => eprintln!("Error sending to peer {}: {}", url, err),
+        }
+        events.failed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Tells the peers about the session as it is now.
+pub fn publish(state: &State, session: &SharedSession) {
+    if let Some(peers) = &state.peers {
+        peers.broadcast(&PeerEvent::SessionChanged {
+            session: session.to_stored(),
+        });
+    }
+}
+
+/// Tells the peers the session of `refresh_token` ended.
+pub fn publish_end(state: &State, refresh_token: &str, reason: &str) {
+    if let Some(peers) = &state.peers {
+        peers.broadcast(&PeerEvent::SessionEnded {
+            refresh_token: refresh_token.to_string(),
+            reason: reason.to_string(),
+        });
+    }
+}
+
+// changes from peers are not passed on, every replica hears from every other
+fn apply(state: &Arc<State>, event: PeerEvent) -> Result<()> {
+    match event {
+        PeerEvent::SessionChanged { session: stored } => {
+            // sent before the session ended and delivered after
+            if state.sessions.is_ended(stored.refresh_token()) {
+                return Ok(());
+            }
+            let socket_key = stored.socket_key().map(str::to_string);
+            let session = match state.sessions.get_by_token(stored.refresh_token()) {
+                Some(session) => {
+                    if session.merge_stored(stored)? {
+                        session
+                            .load()
+                            .notify_sockets(SocketEvent::PermissionsChanged);
+                    }
+                    session
+                }
+                None => {
+                    let session = SharedSession::from_stored(stored)?;
+                    if session.load().get_refresh_jwt().is_expired() {
+                        return Ok(());
+                    }
+                    let session = state.sessions.restore(session);
+                    sweeper::enforce_capacity(state);
+                    session
+                }
+            };
+            if let Some(socket_key) = socket_key {
+                state
+                    .sessions
+                    .insert_socket_key(&socket_key, session.clone());
+            }
+            scheduler::watch(state, &session);
+        }
+        PeerEvent::SessionEnded {
+            refresh_token,
+            reason,
+        } => {
+            if let Some(session) = state.sessions.end_from_peer(&refresh_token) {
+                session.load().notify_sockets(SocketEvent::Close(reason));
+                state.scheduler.cancel_session(&refresh_token);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn handle_request(
+    req: Request<hyper::body::Incoming>,
+    state: Arc<State>,
+) -> Result<Response<Full<Bytes>>> {
+    if req.method() != Method::POST || req.uri().path() != SYNC_PATH {
+        return Ok(utils::status_response(StatusCode::NOT_FOUND, "Not Found"));
+    }
+    let Some(peers) = &state.peers else {
+        return Ok(utils::status_response(StatusCode::NOT_FOUND, "Not Found"));
+    };
+
+    let (parts, body) = req.into_parts();
+    let Some(body) = utils::read_body(body, MAX_BODY_BYTES).await? else {
+        return Ok(utils::status_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "Payload Too Large",
+        ));
+    };
+    if let Err(err) = webhook::verify(
+        &peers.secret,
+        &parts.headers,
+        &body,
+        peers.config.max_age_ms,
+        utils::get_current_unix_timestamp(),
+    ) {
+        eprintln!("Refused peer event: {}", err);
+        return Ok(utils::status_response(
+            StatusCode::UNAUTHORIZED,
+            "Unauthorized",
+        ));
+    }
+
+    let event = match serde_json::from_slice::<PeerEvent>(&body) {
+        Ok(event) => event,
+        Err(err) => {
+            return Ok(utils::status_response(
+                StatusCode::BAD_REQUEST,
+                &format!("invalid peer event: {}", err),
+            ))
+        }
+    };
+    peers.events.received.fetch_add(1, Ordering::Relaxed);
+    if let Err(err) = apply(&state, event) {
+        eprintln!("Error applying peer event: {:?}", err);
+        return Ok(utils::status_response(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            &err.to_string(),
+        ));
+    }
+    Ok(utils::status_response(StatusCode::NO_CONTENT, ""))
+}
+
+/// Listens for the peers on `peers.listening_address`, kept off the public listener.
+pub async fn serve(address: &str, state: Arc<State>) -> Result<()> {
+    let addr: std::net::SocketAddr = address.parse()?;
+    let listener = tokio::net::TcpListener::bind(&addr).await?;
+    println!("Peers listening on http://{addr}");
+
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    println!("Error accepting peer connection: {err:?}");
+                    continue;
+                }
+            };
+            let state = state.clone();
+            tokio::spawn(async move {
+                if let Err(err) = hyper::server::conn::http1::Builder::new()
+                    .serve_connection(
+                        TokioIo::new(stream),
+                        hyper::service::service_fn(move |req| handle_request(req, state.clone())),
+                    )
+                    .await
+                {
+                    println!("Error serving peer connection: {err:?}");
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{permission::Permissions, session::Session};
+
+    fn received(session: &SharedSession) -> StoredSession {
+        let event = serde_json::to_vec(&PeerEvent::SessionChanged {
+            session: session.to_stored(),
+        })
+        .unwrap();
+        let PeerEvent::SessionChanged { session: stored } = serde_json::from_slice(&event).unwrap()
+        else {
+            panic!("not a session change");
+        };
+        stored
+    }
+
+    #[test]
+    fn test_merge_from_peer() {
+        let mut session = Session::for_sub("201944");
+        session.set_socket_session(String::from("local"), String::from("hash"));
+        let local = SharedSession::new(session);
+
+        let mut session = Session::for_sub("201944");
+        session.set_socket_session(String::from("peer"), String::from("hash"));
+        let peer = SharedSession::new(session);
+        let stale = received(&peer);
+        peer.update(|session| {
+            session.apply_permissions(Permissions {
+                permissions: vec![String::from("cta")],
+                ..Default::default()
+            })
+        });
+
+        assert!(local.merge_stored(received(&peer)).unwrap());
+        let merged = local.load();
+        assert_eq!(merged.get_permissions().forwarded(), "cta");
+        // open websockets keep their channel, both keys lead to the session
+        assert_eq!(merged.get_socket_session().unwrap().uuid, "local");
+        assert_eq!(
+            merged.socket_keys().collect::<Vec<&str>>(),
+            ["local", "peer"]
+        );
+
+        // an older copy delivered late changes nothing
+        assert!(!local.merge_stored(stale).unwrap());
+        assert_eq!(local.load().get_permissions().forwarded(), "cta");
+    }
+}
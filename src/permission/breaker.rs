@@ -0,0 +1,80 @@
+use anyhow::Result;
+use futures::future::BoxFuture;
+use std::sync::Arc;
+
+use crate::{circuit_breaker::CircuitBreaker, session::Session};
+
+use super::{PermissionProvider, Permissions};
+
+/// Fails fast with `CircuitOpen` while the provider keeps failing. Only unavailability counts
+/// as a failure, a refusal or an answer that does not parse means the provider is up.
+pub struct BreakerProvider {
+    inner: Box<dyn PermissionProvider>,
+    breaker: Arc<CircuitBreaker>,
+}
+
+impl BreakerProvider {
+    pub fn new(inner: Box<dyn PermissionProvider>, breaker: Arc<CircuitBreaker>) -> Self {
+        BreakerProvider { inner, breaker }
+    }
+
+    async fn lookup(&self, session: &Session) -> Result<Permissions> {
+        // a lookup dropped half way, e.g. the client went away, counts as a failure
+        let probe = self.breaker.acquire()?;
+        let permissions = self.inner.get_permissions(session).await;
+        probe.release(!matches!(&permissions, Err(err) if super::is_unavailable(err)));
+        permissions
+    }
+}
+
+impl PermissionProvider for BreakerProvider {
+    fn get_permissions<'a>(&'a self, session: &'a Session) -> BoxFuture<'a, Result<Permissions>> {
+        Box::pin(self.lookup(session))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{circuit_breaker::BreakerState, config};
+    use reqwest::StatusCode;
+
+    struct Answering(StatusCode);
+
+    impl PermissionProvider for Answering {
+        fn get_permissions<'a>(&'a self, _: &'a Session) -> BoxFuture<'a, Result<Permissions>> {
+            Box::pin(async move { Err(super::super::http::ServiceStatus::new(self.0).into()) })
+        }
+    }
+
+    fn provider(status: StatusCode) -> (BreakerProvider, Arc<CircuitBreaker>) {
+        let breaker = Arc::new(CircuitBreaker::new(
+            "permission_service",
+            config::CircuitBreaker {
+                failure_threshold: 2,
+                open_ms: 60000,
+                ..Default::default()
+            },
+        ));
+        (
+            BreakerProvider::new(Box::new(Answering(status)), breaker.clone()),
+            breaker,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_refusals_leave_the_breaker_closed() {
+        let session = Session::for_sub("201944");
+        let (refusing, breaker) = provider(StatusCode::FORBIDDEN);
+        for _ in 0..5 {
+            assert!(refusing.lookup(&session).await.is_err());
+        }
+        assert_eq!(breaker.state(), BreakerState::Closed);
+
+        let (failing, breaker) = provider(StatusCode::SERVICE_UNAVAILABLE);
+        for _ in 0..2 {
+            assert!(failing.lookup(&session).await.is_err());
+        }
+        assert_eq!(breaker.state(), BreakerState::Open);
+    }
+}
@@ -0,0 +1,306 @@
+use anyhow::Result;
+use futures::future::BoxFuture;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use crate::{config, session::Session};
+
+use super::{PermissionProvider, Permissions};
+
+// while stale permissions are served the provider is asked again at most this often
+const STALE_RETRY: Duration = Duration::from_secs(1);
+
+// refresh tokens remembered per user, past this the provider approves them again
+const MAX_TOKENS: usize = 64;
+
+#[derive(Debug)]
+struct Entry {
+    permissions: Permissions,
+    expires_at: Instant,
+    // a refresh ahead of expiry is running
+    refreshing: bool,
+    // the provider failed, it is not asked again before this
+    retry_at: Option<Instant>,
+    // refresh tokens the provider answered for, tokens are not verified so only these share
+    // the entry
+    tokens: HashSet<String>,
+}
+
+impl Entry {
+    // keeps the tokens approved so far
+    fn renew(
+        entry: &mut Option<Entry>,
+        permissions: Permissions,
+        default_ttl: Duration,
+        now: Instant,
+    ) -> &mut Entry {
+        let ttl = permissions.ttl.unwrap_or(default_ttl);
+        let tokens = entry.take().map(|entry| entry.tokens).unwrap_or_default();
+        entry.insert(Entry {
+            permissions,
+            expires_at: now + ttl,
+            refreshing: false,
+            retry_at: None,
+            tokens,
+        })
+    }
+
+    fn approve(&mut self, token: &str) {
+        if self.tokens.len() >= MAX_TOKENS {
+            self.tokens.clear();
+        }
+        self.tokens.insert(token.to_string());
+    }
+
+    // the session asks again when the entry runs out
+    fn copy(&self, until: Instant, now: Instant) -> Permissions {
+        Permissions {
+            permissions: self.permissions.permissions.clone(),
+            metadata: self.permissions.metadata.clone(),
+            expires_at: self.permissions.expires_at.clone(),
+            ttl: Some(until.saturating_duration_since(now)),
+        }
+    }
+}
+
+// locked across a lookup, so concurrent lookups for a user share the first one's answer
+type Slot = Arc<tokio::sync::Mutex<Option<Entry>>>;
+
+// the token's `iss` and `sub`
+type Key = (String, String);
+
+/// Keeps the permissions of every user by issuer and `sub`, refreshes them in the background
+/// shortly before they expire, and keeps serving them for a while when the provider fails.
+/// A session only gets the cached permissions once the provider answered for its refresh
+/// token, a forged token naming the same user is sent to the provider like any new one.
+pub struct CachedProvider {
+    inner: Arc<dyn PermissionProvider>,
+    config: config::PermissionCache,
+    slots: Mutex<HashMap<Key, Slot>>,
+}
+
+impl CachedProvider {
+    pub fn new(inner: Box<dyn PermissionProvider>, config: config::PermissionCache) -> Self {
+        CachedProvider {
+            inner: Arc::from(inner),
+            config,
+            slots: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn lock_slots(&self) -> MutexGuard<'_, HashMap<Key, Slot>> {
+        self.slots.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn slot(&self, key: Key) -> Slot {
+        let mut slots = self.lock_slots();
+        if slots.len() >= self.config.max_entries && !slots.contains_key(&key) {
+            let now = Instant::now();
+            let grace = Duration::from_millis(self.config.stale_if_error_ms);
+            // slots in use are locked and kept
+            slots.retain(|_, slot| {
+                slot.try_lock().map_or(true, |entry| {
+                    entry
+                        .as_ref()
+                        .is_some_and(|entry| now < entry.expires_at + grace)
+                })
+            });
+        }
+        slots.entry(key).or_default().clone()
+    }
+
+    fn refresh_in_background(&self, session: &Session, slot: Slot) {
+        // the session is borrowed, the refresh gets its own copy of the tokens
+        let session = session.detached();
+
+        let inner = self.inner.clone();
+        let ttl = Duration::from_millis(self.config.ttl_ms);
+        tokio::spawn(async move {
+            let result = inner.get_permissions(&session).await;
+            let mut entry = slot.lock().await;
+            match result {
+                Ok(permissions) => {
+                    Entry::renew(&mut entry, permissions, ttl, Instant::now());
+                }
+                Err(err) => {
+                    eprintln!("Error refreshing permissions: {:#}", err);
+                    if let Some(entry) = entry.as_mut() {
+                        entry.refreshing = false;
+                    }
+                }
+            }
+        });
+    }
+
+    async fn lookup(&self, session: &Session) -> Result<Permissions> {
+        let payload = session.get_access_jwt().get_payload();
+        let slot = self.slot((payload.iss.clone(), payload.sub.clone()));
+        let mut entry = slot.lock().await;
+        let now = Instant::now();
+        let refresh_ahead = Duration::from_millis(self.config.refresh_ahead_ms);
+        let grace = Duration::from_millis(self.config.stale_if_error_ms);
+        let token = session.get_refresh_jwt().get_full_token();
+
+        if let Some(cached) = entry
+            .as_mut()
+            .filter(|cached| cached.tokens.contains(token))
+        {
+            if now < cached.expires_at {
+                if !cached.refreshing && now + refresh_ahead >= cached.expires_at {
+                    cached.refreshing = true;
+                    self.refresh_in_background(session, slot.clone());
+                }
+                return Ok(cached.copy(cached.expires_at, now));
+            }
+            if let Some(retry_at) = cached.retry_at.filter(|retry_at| now < *retry_at) {
+                return Ok(cached.copy(retry_at, now));
+            }
+        }
+
+        match self.inner.get_permissions(session).await {
+            Ok(permissions) => {
+                let ttl = Duration::from_millis(self.config.ttl_ms);
+                let fresh = Entry::renew(&mut entry, permissions, ttl, now);
+                fresh.approve(token);
+                Ok(fresh.copy(fresh.expires_at, now))
+            }
+            Err(err) => match entry.as_mut() {
+                Some(stale) if stale.tokens.contains(token) && now < stale.expires_at + grace => {
+                    eprintln!("Serving stale permissions: {:#}", err);
+                    let retry_at = (now + STALE_RETRY).min(stale.expires_at + grace);
+                    stale.retry_at = Some(retry_at);
+                    Ok(stale.copy(retry_at, now))
+                }
+                _ => Err(err),
+            },
+        }
+    }
+}
+
+impl PermissionProvider for CachedProvider {
+    fn get_permissions<'a>(&'a self, session: &'a Session) -> BoxFuture<'a, Result<Permissions>> {
+        Box::pin(self.lookup(session))
+    }
+
+    fn push<'a>(&'a self, sub: &'a str, permissions: Permissions) -> BoxFuture<'a, Permissions> {
+        Box::pin(async move {
+            let slots = self
+                .lock_slots()
+                .iter()
+                .filter(|((_, cached_sub), _)| cached_sub == sub)
+                .map(|(_, slot)| slot.clone())
+                .collect::<Vec<Slot>>();
+            let ttl = Duration::from_millis(self.config.ttl_ms);
+            // users nobody looked up yet have no approved tokens to keep an entry for
+            for slot in slots {
+                let mut entry = slot.lock().await;
+                if entry.is_some() {
+                    Entry::renew(&mut entry, permissions.clone(), ttl, Instant::now());
+                }
+            }
+            permissions
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+    #[derive(Default)]
+    struct Counting {
+        calls: AtomicU32,
+        failing: AtomicBool,
+    }
+
+    impl PermissionProvider for Arc<Counting> {
+        fn get_permissions<'a>(
+            &'a self,
+            _session: &'a Session,
+        ) -> BoxFuture<'a, Result<Permissions>> {
+            Box::pin(async move {
+                self.calls.fetch_add(1, Ordering::Relaxed);
+                tokio::task::yield_now().await;
+                if self.failing.load(Ordering::Relaxed) {
+                    return Err(anyhow!("permission service is down"));
+                }
+                Ok(Permissions {
+                    permissions: vec![String::from("cta")],
+                    ..Default::default()
+                })
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stale_if_error() {
+        let counting = Arc::new(Counting::default());
+        let cache = CachedProvider::new(
+            Box::new(counting.clone()),
+            config::PermissionCache {
+                ttl_ms: 0,
+                stale_if_error_ms: 60000,
+                ..Default::default()
+            },
+        );
+        let session = Session::for_sub("201944");
+
+        cache.get_permissions(&session).await.unwrap();
+        counting.failing.store(true, Ordering::Relaxed);
+        let stale = cache.get_permissions(&session).await.unwrap();
+        assert_eq!(stale.permissions, vec!["cta"]);
+        // within `STALE_RETRY` the provider is left alone
+        cache.get_permissions(&session).await.unwrap();
+        assert_eq!(counting.calls.load(Ordering::Relaxed), 2);
+
+        // nothing to fall back on
+        let other = Session::for_sub("other");
+        assert!(cache.get_permissions(&other).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_lookups_share_one_call() {
+        let counting = Arc::new(Counting::default());
+        let cache = CachedProvider::new(Box::new(counting.clone()), Default::default());
+        let session = Session::for_sub("201944");
+
+        let (first, second) = tokio::join!(
+            cache.get_permissions(&session),
+            cache.get_permissions(&session)
+        );
+        assert!(first.is_ok() && second.is_ok());
+        assert_eq!(counting.calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_new_tokens_are_approved_by_the_provider() {
+        let counting = Arc::new(Counting::default());
+        let cache = CachedProvider::new(
+            Box::new(counting.clone()),
+            config::PermissionCache {
+                stale_if_error_ms: 60000,
+                ..Default::default()
+            },
+        );
+        cache
+            .get_permissions(&Session::for_token("201944", "example.com", "login"))
+            .await
+            .unwrap();
+
+        // e.g. a forged token naming the same user, the provider gets to reject it
+        counting.failing.store(true, Ordering::Relaxed);
+        let forged = Session::for_token("201944", "example.com", "forged");
+        assert!(cache.get_permissions(&forged).await.is_err());
+        assert_eq!(counting.calls.load(Ordering::Relaxed), 2);
+
+        // users are namespaced by issuer
+        let other_issuer = Session::for_token("201944", "other.com", "login");
+        assert!(cache.get_permissions(&other_issuer).await.is_err());
+        assert_eq!(counting.calls.load(Ordering::Relaxed), 3);
+    }
+}
@@ -0,0 +1,124 @@
+use anyhow::{anyhow, Result};
+use futures::future::{self, BoxFuture};
+
+use crate::{config::ChainMode, session::Session};
+
+use super::{PermissionProvider, Permissions};
+
+/// Combines providers, either using the first one that answers or merging all their answers.
+pub struct ChainProvider {
+    mode: ChainMode,
+    providers: Vec<Box<dyn PermissionProvider>>,
+}
+
+impl ChainProvider {
+    pub fn new(mode: ChainMode, providers: Vec<Box<dyn PermissionProvider>>) -> Self {
+        ChainProvider { mode, providers }
+    }
+
+    async fn first(&self, session: &Session) -> Result<Permissions> {
+        let mut last_err = anyhow!("no permission providers configured");
+        for provider in self.providers.iter() {
+            match provider.get_permissions(session).await {
+                Ok(permissions) => return Ok(permissions),
+                Err(err) => {
+                    eprintln!("Permission provider failed, trying the next one: {:#}", err);
+                    last_err = err;
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    // failing providers are left out as long as one of them answers
+    async fn merge(&self, session: &Session) -> Result<Permissions> {
+        let results = future::join_all(
+            self.providers
+                .iter()
+                .map(|provider| provider.get_permissions(session)),
+        )
+        .await;
+
+        let mut merged: Option<Permissions> = None;
+        let mut last_err = anyhow!("no permission providers configured");
+        for result in results {
+            match (result, merged.as_mut()) {
+                (Ok(permissions), None) => merged = Some(permissions),
+                (Ok(permissions), Some(merged)) => merge_into(merged, permissions),
+                (Err(err), _) => {
+                    eprintln!("Permission provider failed: {:#}", err);
+                    last_err = err;
+                }
+            }
+        }
+        merged.ok_or(last_err)
+    }
+}
+
+// the earlier provider's metadata wins, the shortest ttl applies and a permission lasts as long
+// as the longest grant of it
+fn merge_into(merged: &mut Permissions, mut permissions: Permissions) {
+    for permission in permissions.permissions {
+        let expires_at = permissions.expires_at.remove(&permission);
+        if !merged.permissions.contains(&permission) {
+            if let Some(expires_at) = expires_at {
+                merged.expires_at.insert(permission.clone(), expires_at);
+            }
+            merged.permissions.push(permission);
+        } else if let Some(current) = merged.expires_at.get(&permission).copied() {
+            match expires_at {
+                Some(expires_at) => merged
+                    .expires_at
+                    .insert(permission, current.max(expires_at)),
+                None => merged.expires_at.remove(&permission),
+            };
+        }
+    }
+    for (key, value) in permissions.metadata {
+        merged.metadata.entry(key).or_insert(value);
+    }
+    merged.ttl = match (merged.ttl, permissions.ttl) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    };
+}
+
+impl PermissionProvider for ChainProvider {
+    fn get_permissions<'a>(&'a self, session: &'a Session) -> BoxFuture<'a, Result<Permissions>> {
+        match self.mode {
+            ChainMode::First => Box::pin(self.first(session)),
+            ChainMode::Merge => Box::pin(self.merge(session)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashMap, time::Duration};
+
+    #[test]
+    fn test_merge_into() {
+        let mut merged = Permissions {
+            permissions: vec![String::from("nasdaq")],
+            ttl: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        merge_into(
+            &mut merged,
+            Permissions {
+                permissions: vec![String::from("cta"), String::from("nasdaq")],
+                ttl: Some(Duration::from_secs(30)),
+                expires_at: HashMap::from([(String::from("cta"), 1000)]),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(merged.permissions, vec!["nasdaq", "cta"]);
+        assert_eq!(
+            merged.expires_at,
+            HashMap::from([(String::from("cta"), 1000)])
+        );
+        assert_eq!(merged.ttl, Some(Duration::from_secs(30)));
+    }
+}
@@ -0,0 +1,85 @@
+use anyhow::{anyhow, Result};
+use futures::future::BoxFuture;
+use serde_json::Value;
+
+use crate::{config::Permission, session::Session};
+
+use super::{PermissionProvider, Permissions};
+
+/// Reads the permissions from a claim of the access token, a dotted path like
+/// `realm_access.roles` reaches into nested objects.
+pub struct ClaimProvider {
+    path: Vec<String>,
+}
+
+impl ClaimProvider {
+    pub fn new(claim: &str) -> Self {
+        ClaimProvider {
+            path: claim.split('.').map(String::from).collect(),
+        }
+    }
+
+    fn extract(&self, claims: &Value) -> Result<Vec<Permission>> {
+        let claim = self
+            .path
+            .iter()
+            .try_fold(claims, |value, key| value.get(key))
+            .ok_or_else(|| anyhow!("claim {} not found", self.path.join(".")))?;
+
+        match claim {
+            Value::Array(values) => values
+                .iter()
+                .map(|value| {
+                    value.as_str().map(String::from).ok_or_else(|| {
+                        anyhow!("claim {} is not a list of strings", self.path.join("."))
+                    })
+                })
+                .collect(),
+            // space separated like `scope`
+            Value::String(value) => Ok(value.split_whitespace().map(String::from).collect()),
+            _ => Err(anyhow!(
+                "claim {} is not a list of strings",
+                self.path.join(".")
+            )),
+        }
+    }
+}
+
+impl PermissionProvider for ClaimProvider {
+    fn get_permissions<'a>(&'a self, session: &'a Session) -> BoxFuture<'a, Result<Permissions>> {
+        let permissions = self.extract(session.get_access_jwt().get_claims());
+        Box::pin(async move {
+            Ok(Permissions {
+                permissions: permissions?,
+                ..Default::default()
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract() {
+        let claims = serde_json::json!({
+            "scope": "read write",
+            "realm_access": {"roles": ["nasdaq", "cta"]},
+            "exp": 1,
+        });
+
+        assert_eq!(
+            ClaimProvider::new("realm_access.roles")
+                .extract(&claims)
+                .unwrap(),
+            vec!["nasdaq", "cta"]
+        );
+        assert_eq!(
+            ClaimProvider::new("scope").extract(&claims).unwrap(),
+            vec!["read", "write"]
+        );
+        assert!(ClaimProvider::new("exp").extract(&claims).is_err());
+        assert!(ClaimProvider::new("roles").extract(&claims).is_err());
+    }
+}
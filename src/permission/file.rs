@@ -0,0 +1,80 @@
+use anyhow::Result;
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::{config::Permission, session::Session};
+
+use super::{PermissionProvider, Permissions};
+
+/// Permissions read once from a YAML file, by the token's `sub` and by its `iss`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct StaticProvider {
+    subjects: HashMap<String, Vec<Permission>>,
+    // given to every user of the issuer
+    issuers: HashMap<String, Vec<Permission>>,
+}
+
+impl StaticProvider {
+    pub fn from_file(path: &str) -> Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        Ok(serde_yaml::from_str(&text)?)
+    }
+
+    fn lookup(&self, sub: &str, iss: &str) -> Vec<Permission> {
+        let mut permissions = Vec::new();
+        let found = self
+            .subjects
+            .get(sub)
+            .into_iter()
+            .chain(self.issuers.get(iss));
+        for permission in found.flatten() {
+            if !permissions.contains(permission) {
+                permissions.push(permission.clone());
+            }
+        }
+        permissions
+    }
+}
+
+impl PermissionProvider for StaticProvider {
+    fn get_permissions<'a>(&'a self, session: &'a Session) -> BoxFuture<'a, Result<Permissions>> {
+        let payload = session.get_access_jwt().get_payload();
+        let permissions = self.lookup(&payload.sub, &payload.iss);
+        Box::pin(async move {
+            Ok(Permissions {
+                permissions,
+                ..Default::default()
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let provider: StaticProvider = serde_yaml::from_str(
+            "
+subjects:
+  '201944': [nasdaq, cta]
+issuers:
+  https://example.com: [cta, news]
+",
+        )
+        .unwrap();
+
+        assert_eq!(
+            provider.lookup("201944", "https://example.com"),
+            vec!["nasdaq", "cta", "news"]
+        );
+        assert_eq!(
+            provider.lookup("1", "https://example.com"),
+            vec!["cta", "news"]
+        );
+        assert!(provider.lookup("1", "https://other.com").is_empty());
+    }
+}
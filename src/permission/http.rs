@@ -0,0 +1,353 @@
+use anyhow::{anyhow, Result};
+use futures::future::BoxFuture;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use reqwest::{
+    header::{self, HeaderMap, HeaderName, HeaderValue},
+    Method, RequestBuilder, StatusCode,
+};
+use serde_json::Value;
+use std::{collections::HashMap, fmt, time::Duration};
+use tokio::time::sleep;
+
+use crate::{
+    config::{self, Permission, PermissionServiceAuth},
+    session::Session,
+    utils,
+};
+
+use super::{
+    response::{self, SNIPPET_LENGTH},
+    PermissionProvider, Permissions,
+};
+
+enum Auth {
+    Cookie,
+    Bearer,
+    Service(HeaderName, HeaderValue),
+}
+
+/// The permission service answered something other than a 2xx.
+#[derive(Debug)]
+pub struct ServiceStatus {
+    pub status: StatusCode,
+    snippet: String,
+}
+
+#[cfg(test)]
+impl ServiceStatus {
+    pub fn new(status: StatusCode) -> Self {
+        ServiceStatus {
+            status,
+            snippet: String::new(),
+        }
+    }
+}
+
+impl fmt::Display for ServiceStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "permission service answered {}, body: {:?}",
+            self.status, self.snippet
+        )
+    }
+}
+
+impl std::error::Error for ServiceStatus {}
+
+/// Asks the permission service, see `config::PermissionResponse` for what it answers.
+pub struct HttpProvider {
+    // kept for the pooled connections
+    client: reqwest::Client,
+    retry: config::PermissionClient,
+    url: String,
+    method: Method,
+    auth: Auth,
+    headers: HeaderMap,
+    body: Option<Value>,
+    // `None` asks for every permission
+    filter: Option<Vec<Permission>>,
+    // asks the permission service to push changes to the webhook
+    subscribe: bool,
+    response: config::PermissionResponse,
+    access_token_jwt_cookie_name: String,
+    refresh_token_jwt_cookie_name: String,
+}
+
+/// Every permission the gateway itself looks at, from the routes, rate limits and admission,
+/// and the roles, which the provider has to return for them to be expanded.
+pub fn referenced_permissions(config: &config::Config) -> Vec<Permission> {
+    let admission = config.admission.iter().chain(
+        config
+            .upstreams
+            .values()
+            .filter_map(|pool| pool.admission.as_ref()),
+    );
+    referenced(&config.routes, admission, &config.roles)
+}
+
+fn referenced<'a>(
+    routes: &'a [config::Route],
+    admission: impl Iterator<Item = &'a config::Admission>,
+    roles: &'a HashMap<String, Vec<Permission>>,
+) -> Vec<Permission> {
+    let routes = routes.iter().flat_map(|route| {
+        route.required_permissions.iter().chain(
+            route
+                .rate_limit
+                .iter()
+                .flat_map(|rate_limit| rate_limit.tiers.iter().map(|tier| &tier.permission)),
+        )
+    });
+    let admission = admission.flat_map(|admission| admission.priorities.iter());
+
+    let mut permissions = routes
+        .chain(admission)
+        .map(|permission| permission.to_string())
+        .chain(roles.keys().cloned())
+        .collect::<Vec<Permission>>();
+    permissions.sort();
+    permissions.dedup();
+    permissions
+}
+
+fn is_transient(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+fn render(template: &str, session: &Session, encode: bool) -> String {
+    let payload = session.get_access_jwt().get_payload();
+    let value = |claim: &str| {
+        if encode {
+            utf8_percent_encode(claim, NON_ALPHANUMERIC).to_string()
+        } else {
+            claim.to_string()
+        }
+    };
+    template
+        .replace("{sub}", &value(&payload.sub))
+        .replace("{iss}", &value(&payload.iss))
+}
+
+fn render_body(body: &Value, session: &Session, filter: Option<&[Permission]>) -> Value {
+    match body {
+        Value::String(text) if text == "{filter}" => {
+            Value::from(filter.map(<[Permission]>::to_vec).unwrap_or_default())
+        }
+        Value::String(text) => Value::from(render(text, session, false)),
+        Value::Array(values) => Value::Array(
+            values
+                .iter()
+                .map(|value| render_body(value, session, filter))
+                .collect(),
+        ),
+        Value::Object(values) => Value::Object(
+            values
+                .iter()
+                .map(|(key, value)| (key.clone(), render_body(value, session, filter)))
+                .collect(),
+        ),
+        value => value.clone(),
+    }
+}
+
+impl HttpProvider {
+    pub fn new(service: &config::PermissionService, config: &config::Config) -> Result<Self> {
+        let url = match (&service.url, &config.permission_url) {
+            (Some(url), _) => url.clone(),
+            (None, Some(url)) => url.to_string(),
+            (None, None) => {
+                return Err(anyhow!(
+                    "the http permission provider needs a url or $PERMISSION_URL"
+                ))
+            }
+        };
+        // fail on startup rather than on the first request
+        reqwest::Url::parse(&url.replace("{sub}", "sub").replace("{iss}", "iss"))
+            .map_err(|err| anyhow!("invalid permission service url {}: {}", url, err))?;
+
+        let auth = match &service.auth {
+            PermissionServiceAuth::Cookie => Auth::Cookie,
+            PermissionServiceAuth::Bearer => Auth::Bearer,
+            PermissionServiceAuth::Service { header, env } => {
+                let value = std::env::var(env).map_err(|_| anyhow!("${} is not set", env))?;
+                Auth::Service(header.parse()?, value.parse()?)
+            }
+        };
+
+        let headers = service
+            .headers
+            .iter()
+            .map(|(name, value)| Ok((name.parse()?, value.parse()?)))
+            .collect::<Result<HeaderMap>>()?;
+
+        let retry = service.client.clone();
+        let client = reqwest::Client::builder()
+            .connect_timeout(Duration::from_millis(retry.connect_timeout_ms))
+            .timeout(Duration::from_millis(retry.timeout_ms))
+            .pool_max_idle_per_host(retry.pool_max_idle_per_host)
+            .pool_idle_timeout(Duration::from_millis(retry.pool_idle_timeout_ms))
+            .build()?;
+
+        Ok(HttpProvider {
+            client,
+            retry,
+            url,
+            method: service.method.to_uppercase().parse()?,
+            auth,
+            headers,
+            body: service.body.clone(),
+            filter: service.filter.then(|| referenced_permissions(config)),
+            subscribe: config.permission_webhook.is_some(),
+            response: service.response.clone(),
+            access_token_jwt_cookie_name: config.access_token_jwt_cookie_name.clone(),
+            refresh_token_jwt_cookie_name: config.refresh_token_jwt_cookie_name.clone(),
+        })
+    }
+
+    fn request(&self, session: &Session) -> RequestBuilder {
+        let mut request = self
+            .client
+            .request(self.method.clone(), render(&self.url, session, true))
+            .headers(self.headers.clone());
+
+        request = match &self.auth {
+            Auth::Cookie => request.header(
+                header::COOKIE,
+                format!(
+                    "{}={}; {}={}",
+                    self.access_token_jwt_cookie_name,
+                    session.get_access_jwt().get_full_token(),
+                    self.refresh_token_jwt_cookie_name,
+                    session.get_refresh_jwt().get_full_token()
+                ),
+            ),
+            Auth::Bearer => request.bearer_auth(session.get_access_jwt().get_full_token()),
+            Auth::Service(name, value) => request.header(name, value),
+        };
+
+        if let Some(filter) = &self.filter {
+            request = request.query(&[("filter", filter.join(","))]);
+        }
+        if self.subscribe {
+            request = request.query(&[("subscribe", "true")]);
+        }
+
+        if let Some(body) = &self.body {
+            request = request.json(&render_body(body, session, self.filter.as_deref()));
+        }
+        request
+    }
+
+    async fn fetch(&self, session: &Session) -> Result<Permissions> {
+        let request = self.request(session);
+        let mut attempt = 0;
+
+        let response = loop {
+            let result = request
+                .try_clone()
+                .ok_or_else(|| anyhow!("permission request cannot be retried"))?
+                .send()
+                .await;
+
+            let transient = match &result {
+                Ok(response) => is_transient(response.status()),
+                Err(err) => err.is_connect() || err.is_timeout(),
+            };
+            if !transient || attempt >= self.retry.retries {
+                break result?;
+            }
+
+            match &result {
+                Ok(response) => eprintln!(
+                    "Permission service answered {}, retrying",
+                    response.status()
+                ),
+                Err(err) => eprintln!("Error calling the permission service, retrying: {}", err),
+            }
+            sleep(utils::backoff(
+                self.retry.backoff_ms,
+                self.retry.max_backoff_ms,
+                attempt,
+            ))
+            .await;
+            attempt += 1;
+        };
+
+        let status = response.status();
+        let headers = response.headers().clone();
+        let text = response.text().await?;
+
+        if !status.is_success() {
+            let err = ServiceStatus {
+                status,
+                snippet: text.chars().take(SNIPPET_LENGTH).collect(),
+            };
+            eprintln!("{}", err);
+            return Err(err.into());
+        }
+
+        response::parse(&text, &headers, &self.response).inspect_err(|err| eprintln!("{:#}", err))
+    }
+}
+
+impl PermissionProvider for HttpProvider {
+    fn get_permissions<'a>(&'a self, session: &'a Session) -> BoxFuture<'a, Result<Permissions>> {
+        Box::pin(self.fetch(session))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_server_errors_are_unavailable() {
+        let answer = |status| anyhow::Error::from(ServiceStatus::new(status));
+        assert!(super::super::is_unavailable(&answer(
+            StatusCode::SERVICE_UNAVAILABLE
+        )));
+        assert!(!super::super::is_unavailable(&answer(
+            StatusCode::FORBIDDEN
+        )));
+        assert!(!super::super::is_unavailable(&anyhow!(
+            "invalid permission response"
+        )));
+    }
+
+    #[test]
+    fn test_filter_includes_roles() {
+        let routes = vec![config::Route {
+            path: String::from("/quotes/*"),
+            required_permissions: vec!["nasdaq".into()],
+            ..Default::default()
+        }];
+        let roles = HashMap::from([(
+            String::from("pro_trader"),
+            vec![String::from("nasdaq"), String::from("cta")],
+        )]);
+        assert_eq!(
+            referenced(&routes, std::iter::empty(), &roles),
+            vec!["nasdaq", "pro_trader"]
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        let session = Session::for_sub("a b");
+        assert_eq!(
+            render("http://permissions/users/{sub}", &session, true),
+            "http://permissions/users/a%20b"
+        );
+
+        let body = serde_json::json!({"user": "{sub}@{iss}", "filter": "{filter}", "limit": 10});
+        let filter = vec![String::from("cta")];
+        assert_eq!(
+            render_body(&body, &session, Some(&filter)),
+            serde_json::json!({"user": "a b@example.com", "filter": ["cta"], "limit": 10})
+        );
+    }
+}
@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+
+use crate::config::Permission;
+
+const SEPARATOR: char = ':';
+const WILDCARD: &str = "*";
+
+/// Whether holding `held` grants `required`. Permissions are `:` separated segments, a `*`
+/// segment matches any one segment and a trailing `*` also matches everything below it, so
+/// `market:*` grants `market:nasdaq` and `market:nasdaq:level2` but not `market`.
+pub fn grants(held: &str, required: &str) -> bool {
+    let mut held = held.split(SEPARATOR).peekable();
+    let mut required = required.split(SEPARATOR);
+    loop {
+        match (held.next(), required.next()) {
+            (None, None) => return true,
+            (Some(WILDCARD), Some(_)) if held.peek().is_none() => return true,
+            (Some(held), Some(required)) if held == WILDCARD || held == required => (),
+            _ => return false,
+        }
+    }
+}
+
+/// Whether the permission grants more than itself.
+pub fn is_pattern(permission: &str) -> bool {
+    permission
+        .split(SEPARATOR)
+        .any(|segment| segment == WILDCARD)
+}
+
+/// Adds the permissions of every role in the list, roles may include other roles.
+pub fn expand_roles(
+    roles: &HashMap<String, Vec<Permission>>,
+    permissions: Vec<Permission>,
+) -> Vec<Permission> {
+    let mut expanded = permissions;
+    let mut index = 0;
+    // every name is added once, so cycles between roles end
+    while index < expanded.len() {
+        if let Some(granted) = roles.get(&expanded[index]) {
+            for permission in granted {
+                if !expanded.contains(permission) {
+                    expanded.push(permission.clone());
+                }
+            }
+        }
+        index += 1;
+    }
+    expanded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grants() {
+        assert!(grants("market:nasdaq", "market:nasdaq"));
+        assert!(grants("market:*", "market:nasdaq"));
+        assert!(grants("market:*", "market:nasdaq:level2"));
+        assert!(grants("*:read", "news:read"));
+        assert!(!grants("*:read", "news:write"));
+        assert!(!grants("market:*", "market"));
+        assert!(!grants("market", "market:nasdaq"));
+        assert!(!grants("market:nasdaq", "market:*"));
+    }
+
+    #[test]
+    fn test_expand_roles() {
+        let roles = HashMap::from([
+            (
+                String::from("pro_trader"),
+                vec![String::from("market:*"), String::from("trader")],
+            ),
+            (
+                String::from("trader"),
+                vec![String::from("orders:write"), String::from("pro_trader")],
+            ),
+        ]);
+        assert_eq!(
+            expand_roles(
+                &roles,
+                vec![String::from("pro_trader"), String::from("news")]
+            ),
+            vec!["pro_trader", "news", "market:*", "trader", "orders:write"]
+        );
+    }
+}
@@ -0,0 +1,118 @@
+use anyhow::{anyhow, Result};
+use futures::future::BoxFuture;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use crate::{
+    circuit_breaker::{CircuitBreaker, CircuitOpen},
+    config::{self, Permission},
+    session::Session,
+};
+
+mod breaker;
+mod cache;
+mod chain;
+mod claim;
+mod file;
+mod http;
+mod matching;
+mod registry;
+mod response;
+mod roles;
+
+pub use matching::expand_roles;
+pub use registry::{registered, PermissionSet, RequiredPermission};
+pub use response::parse_update;
+
+pub type Metadata = serde_json::Map<String, serde_json::Value>;
+
+/// What a provider knows about a user.
+#[derive(Debug, Clone, Default)]
+pub struct Permissions {
+    pub permissions: Vec<Permission>,
+    // passed on to the upstream as `X-Permission-Metadata`
+    pub metadata: Metadata,
+    // unix seconds at which a permission stops being granted, missing ones do not expire
+    pub expires_at: HashMap<Permission, u64>,
+    // how long the permissions may be used before asking again, until the token expires if `None`
+    pub ttl: Option<Duration>,
+}
+
+/// Looks up the permissions of the user a session belongs to.
+pub trait PermissionProvider: Send + Sync {
+    fn get_permissions<'a>(&'a self, session: &'a Session) -> BoxFuture<'a, Result<Permissions>>;
+
+    /// Takes permissions the permission service pushed for `sub`, for providers that keep them,
+    /// and returns them the way sessions should see them.
+    fn push<'a>(&'a self, _sub: &'a str, permissions: Permissions) -> BoxFuture<'a, Permissions> {
+        Box::pin(async { permissions })
+    }
+}
+
+/// The configured provider behind the permission service breaker and the cache, with roles
+/// expanded on the way out.
+pub fn build(
+    config: &config::Config,
+    breaker: Option<Arc<CircuitBreaker>>,
+) -> Result<Box<dyn PermissionProvider>> {
+    let mut provider = from_config(&config.permission_provider, config)?;
+    if let Some(breaker) = breaker {
+        provider = Box::new(breaker::BreakerProvider::new(provider, breaker));
+    }
+    if let Some(cache) = &config.permission_cache {
+        provider = Box::new(cache::CachedProvider::new(provider, cache.clone()));
+    }
+    if !config.roles.is_empty() {
+        provider = Box::new(roles::RoleProvider::new(provider, config.roles.clone()));
+    }
+    Ok(provider)
+}
+
+/// Whether `err` means the permission service could not be asked rather than that it refused:
+/// transport errors and timeouts, 5xx answers and an open breaker.
+pub fn is_unavailable(err: &anyhow::Error) -> bool {
+    err.is::<CircuitOpen>()
+        || err.is::<reqwest::Error>()
+        || err
+            .downcast_ref::<http::ServiceStatus>()
+            .is_some_and(|answer| answer.status.is_server_error())
+}
+
+// anyone can mint a token with the claims these trust, the gateway does not check signatures
+fn require_insecure(provider: &str, config: &config::Config) -> Result<()> {
+    if config.insecure_unverified_tokens {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "the {} permission provider grants whatever an unverified token claims, \
+         set insecure_unverified_tokens to use it anyway",
+        provider
+    ))
+}
+
+fn from_config(
+    provider: &config::PermissionProvider,
+    config: &config::Config,
+) -> Result<Box<dyn PermissionProvider>> {
+    Ok(match provider {
+        config::PermissionProvider::Http(service) => {
+            Box::new(http::HttpProvider::new(service, config)?)
+        }
+        config::PermissionProvider::Static { path } => {
+            require_insecure("static", config)?;
+            Box::new(file::StaticProvider::from_file(path)?)
+        }
+        config::PermissionProvider::JwtClaim { claim } => {
+            require_insecure("jwt_claim", config)?;
+            Box::new(claim::ClaimProvider::new(claim))
+        }
+        config::PermissionProvider::Chain { mode, providers } => {
+            Box::new(chain::ChainProvider::new(
+                *mode,
+                providers
+                    .iter()
+                    .map(|provider| from_config(provider, config))
+                    .collect::<Result<Vec<Box<dyn PermissionProvider>>>>()?,
+            ))
+        }
+    })
+}
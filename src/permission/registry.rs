@@ -0,0 +1,304 @@
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    ops::Deref,
+    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use crate::config::Permission;
+
+use super::matching;
+
+lazy_static::lazy_static! {
+    static ref REGISTRY: Registry = Registry::default();
+}
+
+#[derive(Debug)]
+struct Entry {
+    name: Arc<str>,
+    // permission sets holding the id, it is freed at zero
+    refs: usize,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    ids: HashMap<Arc<str>, u32>,
+    // by id, `None` once freed
+    entries: Vec<Option<Entry>>,
+    // freed ids, handed out again before new ones
+    free: Vec<u32>,
+}
+
+/// Gives every permission some session holds a small id, ids of permissions nobody holds any
+/// more are freed and reused.
+#[derive(Debug, Default)]
+struct Registry {
+    inner: RwLock<Inner>,
+}
+
+impl Registry {
+    fn read(&self) -> RwLockReadGuard<'_, Inner> {
+        self.inner.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Inner> {
+        self.inner.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn acquire(&self, name: &str) -> (u32, Arc<str>) {
+        let mut inner = self.write();
+        if let Some(&id) = inner.ids.get(name) {
+            let entry = inner.entries[id as usize]
+                .as_mut()
+                .expect("registered ids have an entry");
+            entry.refs += 1;
+            return (id, entry.name.clone());
+        }
+
+        let name: Arc<str> = Arc::from(name);
+        let entry = Entry {
+            name: name.clone(),
+            refs: 1,
+        };
+        let id = match inner.free.pop() {
+            Some(id) => {
+                inner.entries[id as usize] = Some(entry);
+                id
+            }
+            None => {
+                inner.entries.push(Some(entry));
+                (inner.entries.len() - 1) as u32
+            }
+        };
+        inner.ids.insert(name.clone(), id);
+        (id, name)
+    }
+
+    fn retain(&self, ids: &[u32]) {
+        let mut inner = self.write();
+        for &id in ids {
+            if let Some(entry) = inner.entries[id as usize].as_mut() {
+                entry.refs += 1;
+            }
+        }
+    }
+
+    fn release(&self, ids: &[u32]) {
+        let mut inner = self.write();
+        for &id in ids {
+            let Some(entry) = inner.entries[id as usize].as_mut() else {
+                continue;
+            };
+            entry.refs -= 1;
+            if entry.refs == 0 {
+                let name = entry.name.clone();
+                inner.ids.remove(&name);
+                inner.entries[id as usize] = None;
+                inner.free.push(id);
+            }
+        }
+    }
+
+    fn id(&self, name: &str) -> Option<u32> {
+        self.read().ids.get(name).copied()
+    }
+}
+
+/// Permissions currently registered, for the metrics.
+pub fn registered() -> usize {
+    REGISTRY.read().ids.len()
+}
+
+/// A permission the gateway checks, from the routes, rate limit tiers and admission priorities.
+/// Its id is resolved once when the config is read and held for as long as it is, so checking
+/// it against a set never looks the name up.
+#[derive(Debug, Deserialize)]
+#[serde(from = "String")]
+pub struct RequiredPermission {
+    id: u32,
+    name: Arc<str>,
+}
+
+impl From<&str> for RequiredPermission {
+    fn from(name: &str) -> Self {
+        let (id, name) = REGISTRY.acquire(name);
+        RequiredPermission { id, name }
+    }
+}
+
+impl From<String> for RequiredPermission {
+    fn from(name: String) -> Self {
+        RequiredPermission::from(name.as_str())
+    }
+}
+
+impl Deref for RequiredPermission {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Clone for RequiredPermission {
+    fn clone(&self) -> Self {
+        REGISTRY.retain(&[self.id]);
+        RequiredPermission {
+            id: self.id,
+            name: self.name.clone(),
+        }
+    }
+}
+
+impl Drop for RequiredPermission {
+    fn drop(&mut self) {
+        REGISTRY.release(&[self.id]);
+    }
+}
+
+/// A set of permissions as registry ids. Membership is a bit test, wildcard permissions are
+/// kept aside and matched one by one, and the list forwarded upstream is joined once.
+#[derive(Debug, Default)]
+pub struct PermissionSet {
+    // in the order they were granted
+    ids: Vec<u32>,
+    names: Vec<Arc<str>>,
+    bits: Vec<u64>,
+    patterns: Vec<Arc<str>>,
+    forwarded: String,
+}
+
+impl PermissionSet {
+    pub fn new<'a>(permissions: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut set = PermissionSet::default();
+        for permission in permissions {
+            if set.contains(permission) {
+                continue;
+            }
+            let (id, name) = REGISTRY.acquire(permission);
+            let (word, bit) = (id as usize / 64, id % 64);
+            if set.bits.len() <= word {
+                set.bits.resize(word + 1, 0);
+            }
+            set.bits[word] |= 1 << bit;
+            if matching::is_pattern(&name) {
+                set.patterns.push(name.clone());
+            }
+            set.ids.push(id);
+            set.names.push(name);
+        }
+        set.forwarded = set
+            .names
+            .iter()
+            .map(|name| &**name)
+            .collect::<Vec<&str>>()
+            .join(",");
+        set
+    }
+
+    fn has_id(&self, id: u32) -> bool {
+        self.bits
+            .get(id as usize / 64)
+            .is_some_and(|word| word & (1 << (id % 64)) != 0)
+    }
+
+    /// Whether exactly this permission is in the set.
+    pub fn contains(&self, permission: &str) -> bool {
+        !self.ids.is_empty() && REGISTRY.id(permission).is_some_and(|id| self.has_id(id))
+    }
+
+    /// Whether a permission in the set grants `required`, see `matching::grants`.
+    pub fn holds(&self, required: &RequiredPermission) -> bool {
+        self.has_id(required.id)
+            || self
+                .patterns
+                .iter()
+                .any(|pattern| matching::grants(pattern, required))
+    }
+
+    /// The permissions in the set that one of `allowed` grants.
+    pub fn restrict(&self, allowed: &[Permission]) -> PermissionSet {
+        PermissionSet::new(self.names.iter().map(|name| &**name).filter(|name| {
+            allowed
+                .iter()
+                .any(|pattern| matching::grants(pattern, name))
+        }))
+    }
+
+    pub fn names(&self) -> &[Arc<str>] {
+        &self.names
+    }
+
+    /// The comma separated list sent upstream.
+    pub fn forwarded(&self) -> &str {
+        &self.forwarded
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+}
+
+impl Clone for PermissionSet {
+    fn clone(&self) -> Self {
+        REGISTRY.retain(&self.ids);
+        PermissionSet {
+            ids: self.ids.clone(),
+            names: self.names.clone(),
+            bits: self.bits.clone(),
+            patterns: self.patterns.clone(),
+            forwarded: self.forwarded.clone(),
+        }
+    }
+}
+
+impl Drop for PermissionSet {
+    fn drop(&mut self) {
+        REGISTRY.release(&self.ids);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_permission_set() {
+        let set = PermissionSet::new(["registry:cta", "registry:news:*", "registry:cta"]);
+        assert_eq!(set.forwarded(), "registry:cta,registry:news:*");
+        assert!(set.contains("registry:cta"));
+        assert!(!set.contains("registry:news:reuters"));
+        assert!(set.holds(&"registry:cta".into()));
+        assert!(set.holds(&"registry:news:reuters".into()));
+        assert!(!set.holds(&"registry:nasdaq".into()));
+        assert!(!PermissionSet::new([]).holds(&"registry:cta".into()));
+
+        let safe = set.restrict(&[String::from("registry:news:*")]);
+        assert_eq!(safe.forwarded(), "registry:news:*");
+    }
+
+    #[test]
+    fn test_unreferenced_permissions_are_freed() {
+        let set = PermissionSet::new(["gc:trial"]);
+        let id = REGISTRY.id("gc:trial").unwrap();
+        let copy = set.clone();
+        drop(set);
+        assert_eq!(REGISTRY.id("gc:trial"), Some(id));
+
+        drop(copy);
+        assert_eq!(REGISTRY.id("gc:trial"), None);
+    }
+
+    #[test]
+    fn test_required_permission_keeps_its_id() {
+        let required = RequiredPermission::from("required:cta");
+        let set = PermissionSet::new(["required:cta"]);
+        drop(set);
+        // a set granted later gets the id the requirement was resolved to
+        let set = PermissionSet::new(["required:cta"]);
+        assert!(set.holds(&required));
+        drop(set);
+        drop(required);
+        assert_eq!(REGISTRY.id("required:cta"), None);
+    }
+}
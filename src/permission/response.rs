@@ -0,0 +1,258 @@
+use anyhow::{anyhow, Context, Result};
+use reqwest::header::{self, HeaderMap};
+use serde_json::Value;
+use std::time::Duration;
+
+use crate::{
+    config::{self, ResponseFormat},
+    utils,
+};
+
+use super::Permissions;
+
+// how much of a body that could not be parsed ends up in the logs
+pub const SNIPPET_LENGTH: usize = 200;
+
+fn describe(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+/// `max-age` of `Cache-Control`, zero when the response must not be reused.
+pub fn max_age(headers: &HeaderMap) -> Option<Duration> {
+    let cache_control = headers.get(header::CACHE_CONTROL)?.to_str().ok()?;
+    cache_control
+        .split(',')
+        .map(str::trim)
+        .find_map(|directive| match directive {
+            "no-store" | "no-cache" => Some(0),
+            _ => directive
+                .strip_prefix("max-age=")
+                .and_then(|seconds| seconds.parse().ok()),
+        })
+        .map(Duration::from_secs)
+}
+
+fn parse_item(index: usize, item: &Value, parsed: &mut Permissions, now: u64) -> Result<()> {
+    let (name, expires_at, metadata) = match item {
+        Value::String(name) => (name, None, None),
+        Value::Object(object) => {
+            let name = match object.get("name") {
+                Some(Value::String(name)) => name,
+                Some(other) => {
+                    return Err(anyhow!(
+                        "item {}: name is {}, expected a string",
+                        index,
+                        describe(other)
+                    ))
+                }
+                None => return Err(anyhow!("item {}: name is missing", index)),
+            };
+            let expires_at = match object.get("expires_at") {
+                None | Some(Value::Null) => None,
+                Some(value) => Some(value.as_u64().ok_or_else(|| {
+                    anyhow!(
+                        "item {}: expires_at is {}, expected unix seconds",
+                        index,
+                        describe(value)
+                    )
+                })?),
+            };
+            (name, expires_at, object.get("metadata"))
+        }
+        other => {
+            return Err(anyhow!(
+                "item {} is {}, expected a string or an object",
+                index,
+                describe(other)
+            ))
+        }
+    };
+
+    if let Some(expires_at) = expires_at {
+        if expires_at <= now {
+            return Ok(());
+        }
+        // looked up again once the first permission runs out
+        let ttl = Duration::from_secs(expires_at - now);
+        parsed.ttl = Some(parsed.ttl.map_or(ttl, |current| current.min(ttl)));
+        parsed.expires_at.insert(name.clone(), expires_at);
+    }
+    if let Some(metadata) = metadata {
+        parsed.metadata.insert(name.clone(), metadata.clone());
+    }
+    if !parsed.permissions.contains(name) {
+        parsed.permissions.push(name.clone());
+    }
+    Ok(())
+}
+
+fn parse_json(text: &str, pointer: &str, now: u64) -> Result<Permissions> {
+    let body: Value = serde_json::from_str(text).context("invalid JSON")?;
+    let list = body
+        .pointer(pointer)
+        .ok_or_else(|| anyhow!("nothing at {:?}", pointer))?;
+    let Value::Array(items) = list else {
+        return Err(anyhow!(
+            "{} at {:?}, expected an array",
+            describe(list),
+            pointer
+        ));
+    };
+
+    let mut parsed = Permissions::default();
+    for (index, item) in items.iter().enumerate() {
+        parse_item(index, item, &mut parsed, now)?;
+    }
+    Ok(parsed)
+}
+
+fn parse_text(text: &str) -> Permissions {
+    let mut parsed = Permissions::default();
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        if !parsed
+            .permissions
+            .iter()
+            .any(|permission| permission == line)
+        {
+            parsed.permissions.push(line.to_string());
+        }
+    }
+    parsed
+}
+
+/// Reads the permissions out of the permission service's answer.
+pub fn parse(
+    text: &str,
+    headers: &HeaderMap,
+    response: &config::PermissionResponse,
+) -> Result<Permissions> {
+    let now = utils::get_current_unix_timestamp();
+    let mut parsed = match response.format {
+        ResponseFormat::Json => parse_json(text, &response.pointer, now).map_err(|err| {
+            let snippet = text.chars().take(SNIPPET_LENGTH).collect::<String>();
+            anyhow!(
+                "invalid permission response: {:#}, body: {:?}",
+                err,
+                snippet
+            )
+        })?,
+        ResponseFormat::Text => parse_text(text),
+    };
+
+    if let Some(max_age) = max_age(headers) {
+        parsed.ttl = Some(parsed.ttl.map_or(max_age, |ttl| ttl.min(max_age)));
+    }
+    Ok(parsed)
+}
+
+/// A permission change pushed to the webhook.
+#[derive(Debug)]
+pub struct PermissionUpdate {
+    pub sub: String,
+    // grows with every change the permission service sends for `sub`
+    pub sequence: u64,
+    pub permissions: Permissions,
+}
+
+/// Reads a permission change pushed to the webhook, `{"sub": ..., "sequence": ..., "permissions":
+/// [...]}` with the items of a JSON response.
+pub fn parse_update(text: &str) -> Result<PermissionUpdate> {
+    let body: Value = serde_json::from_str(text).context("invalid JSON")?;
+    let sub = match body.get("sub") {
+        Some(Value::String(sub)) => sub.clone(),
+        Some(other) => return Err(anyhow!("sub is {}, expected a string", describe(other))),
+        None => return Err(anyhow!("sub is missing")),
+    };
+    let sequence = match body.get("sequence") {
+        Some(value) => value.as_u64().ok_or_else(|| {
+            anyhow!(
+                "sequence is {}, expected an unsigned integer",
+                describe(value)
+            )
+        })?,
+        None => return Err(anyhow!("sequence is missing")),
+    };
+    let permissions = parse_json(text, "/permissions", utils::get_current_unix_timestamp())?;
+    Ok(PermissionUpdate {
+        sub,
+        sequence,
+        permissions,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn test_parse_json() {
+        let text = r#"{"data": {"permissions": [
+            "cta",
+            {"name": "nasdaq", "expires_at": 1060, "metadata": {"plan": "pro"}},
+            {"name": "expired", "expires_at": 1000}
+        ]}}"#;
+
+        let parsed = parse_json(text, "/data/permissions", 1000).unwrap();
+        assert_eq!(parsed.permissions, vec!["cta", "nasdaq"]);
+        assert_eq!(parsed.metadata["nasdaq"]["plan"], "pro");
+        assert_eq!(parsed.ttl, Some(Duration::from_secs(60)));
+        assert_eq!(parsed.expires_at.get("nasdaq"), Some(&1060));
+        assert!(!parsed.expires_at.contains_key("cta"));
+
+        let bare = parse_json(r#"["cta"]"#, "", 1000).unwrap();
+        assert_eq!(bare.permissions, vec!["cta"]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = parse_json(r#"{"data": {}}"#, "/data", 0).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"an object at "/data", expected an array"#
+        );
+
+        let err = parse_json(r#"[{"expires_at": 1}]"#, "", 0).unwrap_err();
+        assert_eq!(err.to_string(), "item 0: name is missing");
+    }
+
+    #[test]
+    fn test_parse_update() {
+        let update = parse_update(
+            r#"{"sub": "201944", "sequence": 7, "permissions": ["cta", {"name": "nasdaq"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(update.sub, "201944");
+        assert_eq!(update.sequence, 7);
+        assert_eq!(update.permissions.permissions, vec!["cta", "nasdaq"]);
+
+        let err = parse_update(r#"{"sequence": 1, "permissions": []}"#).unwrap_err();
+        assert_eq!(err.to_string(), "sub is missing");
+        let err = parse_update(r#"{"sub": "201944", "permissions": []}"#).unwrap_err();
+        assert_eq!(err.to_string(), "sequence is missing");
+    }
+
+    #[test]
+    fn test_parse_text_and_max_age() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("private, max-age=30"),
+        );
+        let response = config::PermissionResponse {
+            format: ResponseFormat::Text,
+            ..Default::default()
+        };
+
+        let parsed = parse("nasdaq\n\n cta \n", &headers, &response).unwrap();
+        assert_eq!(parsed.permissions, vec!["nasdaq", "cta"]);
+        assert_eq!(parsed.ttl, Some(Duration::from_secs(30)));
+    }
+}
@@ -0,0 +1,95 @@
+use anyhow::Result;
+use futures::future::BoxFuture;
+use std::collections::{HashMap, HashSet};
+
+use crate::{config::Permission, session::Session};
+
+use super::{matching, PermissionProvider, Permissions};
+
+/// Adds the permissions of the roles a user holds to whatever the provider returned.
+pub struct RoleProvider {
+    inner: Box<dyn PermissionProvider>,
+    roles: HashMap<String, Vec<Permission>>,
+}
+
+impl RoleProvider {
+    pub fn new(
+        inner: Box<dyn PermissionProvider>,
+        roles: HashMap<String, Vec<Permission>>,
+    ) -> Self {
+        RoleProvider { inner, roles }
+    }
+
+    // a permission granted through roles lasts as long as the longest grant leading to it
+    fn expand(&self, mut permissions: Permissions) -> Permissions {
+        let mut expanded = Vec::new();
+        let mut expires_at = HashMap::new();
+        let mut permanent = HashSet::new();
+        for held in &permissions.permissions {
+            let until = permissions.expires_at.get(held).copied();
+            for permission in matching::expand_roles(&self.roles, vec![held.clone()]) {
+                match until {
+                    Some(until) => {
+                        let current = expires_at.entry(permission.clone()).or_insert(until);
+                        *current = until.max(*current);
+                    }
+                    None => {
+                        permanent.insert(permission.clone());
+                    }
+                }
+                if !expanded.contains(&permission) {
+                    expanded.push(permission);
+                }
+            }
+        }
+        expires_at.retain(|permission, _| !permanent.contains(permission));
+
+        permissions.permissions = expanded;
+        permissions.expires_at = expires_at;
+        permissions
+    }
+
+    async fn lookup(&self, session: &Session) -> Result<Permissions> {
+        Ok(self.expand(self.inner.get_permissions(session).await?))
+    }
+}
+
+impl PermissionProvider for RoleProvider {
+    fn get_permissions<'a>(&'a self, session: &'a Session) -> BoxFuture<'a, Result<Permissions>> {
+        Box::pin(self.lookup(session))
+    }
+
+    fn push<'a>(&'a self, sub: &'a str, permissions: Permissions) -> BoxFuture<'a, Permissions> {
+        Box::pin(self.inner.push(sub, self.expand(permissions)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_role_expiry() {
+        let provider = RoleProvider::new(
+            Box::new(super::super::claim::ClaimProvider::new("permissions")),
+            HashMap::from([(
+                String::from("trial"),
+                vec![String::from("market:*"), String::from("news")],
+            )]),
+        );
+        let expanded = provider.expand(Permissions {
+            permissions: vec![String::from("trial"), String::from("news")],
+            expires_at: HashMap::from([(String::from("trial"), 1000)]),
+            ..Default::default()
+        });
+
+        assert_eq!(expanded.permissions, vec!["trial", "market:*", "news"]);
+        assert_eq!(
+            expanded.expires_at,
+            HashMap::from([
+                (String::from("trial"), 1000),
+                (String::from("market:*"), 1000)
+            ])
+        );
+    }
+}
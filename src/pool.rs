@@ -0,0 +1,435 @@
+use anyhow::{anyhow, Result};
+use http_body_util::Full;
+use hyper::{body::Bytes, Request, Uri};
+use rand::Rng;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{
+    admission::Admission,
+    circuit_breaker::CircuitBreaker,
+    concurrency_limit::ConcurrencyLimit,
+    config::{self, Strategy},
+    upstream::HttpClient,
+    utils,
+};
+
+// points per endpoint on the consistent hash ring
+const VIRTUAL_NODES: usize = 100;
+
+fn hash<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[derive(Debug)]
+pub struct Endpoint {
+    pub uri: Uri,
+    active: AtomicUsize,
+    // result of the active health checks
+    healthy: AtomicBool,
+    health_check_streak: AtomicU32,
+    consecutive_failures: AtomicU32,
+    ejected_until_ms: AtomicU64,
+    // when the endpoint last became available, used for slow start
+    available_since_ms: AtomicU64,
+}
+
+impl Endpoint {
+    fn new(uri: Uri) -> Self {
+        Endpoint {
+            uri,
+            active: AtomicUsize::new(0),
+            healthy: AtomicBool::new(true),
+            health_check_streak: AtomicU32::new(0),
+            consecutive_failures: AtomicU32::new(0),
+            ejected_until_ms: AtomicU64::new(0),
+            available_since_ms: AtomicU64::new(0),
+        }
+    }
+
+    fn is_available(&self, now_ms: u64) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+            && self.ejected_until_ms.load(Ordering::Relaxed) <= now_ms
+    }
+
+    // share of the traffic between 0.1 and 1 while slow starting
+    fn weight(&self, now_ms: u64, slow_start_ms: u64) -> f64 {
+        let elapsed = now_ms.saturating_sub(self.available_since_ms.load(Ordering::Relaxed));
+        if slow_start_ms == 0 || elapsed >= slow_start_ms {
+            1.0
+        } else {
+            (elapsed as f64 / slow_start_ms as f64).max(0.1)
+        }
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+}
+
+/// Counts an in flight request or an open websocket against the endpoint until dropped.
+#[derive(Debug)]
+pub struct EndpointGuard {
+    pub endpoint: Arc<Endpoint>,
+}
+
+impl Drop for EndpointGuard {
+    fn drop(&mut self) {
+        self.endpoint.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+pub struct Pool {
+    name: String,
+    config: config::UpstreamPool,
+    breaker: Option<CircuitBreaker>,
+    admission: Option<Arc<Admission>>,
+    concurrency_limit: Option<Arc<ConcurrencyLimit>>,
+    endpoints: Vec<Arc<Endpoint>>,
+    // sorted (hash, endpoint index)
+    ring: Vec<(u64, usize)>,
+    next: AtomicUsize,
+}
+
+impl Pool {
+    pub fn new(
+        name: &str,
+        config: config::UpstreamPool,
+        default_breaker: Option<&config::CircuitBreaker>,
+        default_admission: Option<&config::Admission>,
+        default_concurrency: Option<&config::AdaptiveConcurrency>,
+    ) -> Result<Self> {
+        let endpoints = config
+            .endpoints
+            .iter()
+            .map(|endpoint| {
+                let uri: Uri = endpoint.parse()?;
+                uri.authority()
+                    .ok_or_else(|| anyhow!("endpoint {} has no authority", endpoint))?;
+                Ok(Arc::new(Endpoint::new(uri)))
+            })
+            .collect::<Result<Vec<Arc<Endpoint>>>>()?;
+
+        if endpoints.is_empty() {
+            return Err(anyhow!("upstream pool has no endpoints"));
+        }
+
+        let mut ring = endpoints
+            .iter()
+            .enumerate()
+            .flat_map(|(index, endpoint)| {
+                (0..VIRTUAL_NODES).map(move |node| (hash(&(endpoint.uri.to_string(), node)), index))
+            })
+            .collect::<Vec<(u64, usize)>>();
+        ring.sort_unstable();
+
+        let breaker = config
+            .circuit_breaker
+            .as_ref()
+            .or(default_breaker)
+            .map(|breaker| CircuitBreaker::new(&format!("upstream {}", name), breaker.clone()));
+
+        let admission = config
+            .admission
+            .as_ref()
+            .or(default_admission)
+            .map(|admission| Arc::new(Admission::new(admission.clone())));
+
+        let concurrency_limit = config
+            .adaptive_concurrency
+            .as_ref()
+            .or(default_concurrency)
+            .map(|concurrency| Arc::new(ConcurrencyLimit::new(concurrency.clone())));
+
+        Ok(Pool {
+            name: name.to_string(),
+            config,
+            breaker,
+            admission,
+            concurrency_limit,
+            endpoints,
+            ring,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn single(
+        name: &str,
+        uri: &Uri,
+        default_breaker: Option<&config::CircuitBreaker>,
+        default_admission: Option<&config::Admission>,
+        default_concurrency: Option<&config::AdaptiveConcurrency>,
+    ) -> Result<Self> {
+        Pool::new(
+            name,
+            config::UpstreamPool {
+                endpoints: vec![uri.to_string()],
+                ..Default::default()
+            },
+            default_breaker,
+            default_admission,
+            default_concurrency,
+        )
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn breaker(&self) -> Option<&CircuitBreaker> {
+        self.breaker.as_ref()
+    }
+
+    pub fn admission(&self) -> Option<&Arc<Admission>> {
+        self.admission.as_ref()
+    }
+
+    pub fn concurrency_limit(&self) -> Option<&Arc<ConcurrencyLimit>> {
+        self.concurrency_limit.as_ref()
+    }
+
+    pub fn endpoints(&self) -> &[Arc<Endpoint>] {
+        &self.endpoints
+    }
+
+    pub fn is_healthy(endpoint: &Endpoint) -> bool {
+        endpoint.is_available(utils::get_current_unix_timestamp_ms())
+    }
+
+    fn round_robin(&self, now_ms: u64) -> Option<&Arc<Endpoint>> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let available = (0..self.endpoints.len())
+            .map(|offset| &self.endpoints[(start + offset) % self.endpoints.len()])
+            .filter(|endpoint| endpoint.is_available(now_ms));
+
+        let mut fallback = None;
+        let mut rng = rand::thread_rng();
+        for endpoint in available {
+            // slow starting endpoints are skipped part of the time
+            if rng.gen_bool(endpoint.weight(now_ms, self.config.slow_start_ms)) {
+                return Some(endpoint);
+            }
+            fallback.get_or_insert(endpoint);
+        }
+        fallback
+    }
+
+    fn least_connections(&self, now_ms: u64) -> Option<&Arc<Endpoint>> {
+        let score = |endpoint: &Arc<Endpoint>| {
+            (endpoint.active_connections() + 1) as f64
+                / endpoint.weight(now_ms, self.config.slow_start_ms)
+        };
+
+        // start at a rotating offset so ties are spread
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..self.endpoints.len())
+            .map(|offset| &self.endpoints[(start + offset) % self.endpoints.len()])
+            .filter(|endpoint| endpoint.is_available(now_ms))
+            .min_by(|a, b| score(a).total_cmp(&score(b)))
+    }
+
+    fn consistent_hash(&self, key: &str, now_ms: u64) -> Option<&Arc<Endpoint>> {
+        let key = hash(key);
+        let start = self.ring.partition_point(|(point, _)| *point < key);
+        (0..self.ring.len())
+            .map(|offset| &self.endpoints[self.ring[(start + offset) % self.ring.len()].1])
+            .find(|endpoint| endpoint.is_available(now_ms))
+    }
+
+    /// Picks an endpoint, `None` when every endpoint is unhealthy or ejected.
+    pub fn pick(&self, hash_key: Option<&str>) -> Option<EndpointGuard> {
+        let now_ms = utils::get_current_unix_timestamp_ms();
+        let endpoint = match (self.config.strategy, hash_key) {
+            (Strategy::ConsistentHash, Some(key)) => self.consistent_hash(key, now_ms),
+            (Strategy::LeastConnections, _) => self.least_connections(now_ms),
+            _ => self.round_robin(now_ms),
+        }?;
+
+        endpoint.active.fetch_add(1, Ordering::Relaxed);
+        Some(EndpointGuard {
+            endpoint: endpoint.clone(),
+        })
+    }
+
+    /// Passive ejection, called with the outcome of every request sent to the endpoint.
+    pub fn report(&self, endpoint: &Endpoint, success: bool) {
+        if success {
+            endpoint.consecutive_failures.store(0, Ordering::Relaxed);
+            return;
+        }
+
+        let failures = endpoint
+            .consecutive_failures
+            .fetch_add(1, Ordering::Relaxed)
+            + 1;
+        let ejection = &self.config.passive_ejection;
+        if ejection.consecutive_failures > 0 && failures >= ejection.consecutive_failures {
+            let until = utils::get_current_unix_timestamp_ms() + ejection.ejection_ms;
+            endpoint.consecutive_failures.store(0, Ordering::Relaxed);
+            endpoint.ejected_until_ms.store(until, Ordering::Relaxed);
+            endpoint.available_since_ms.store(until, Ordering::Relaxed);
+            println!(
+                "Ejecting upstream endpoint {} for {}ms",
+                endpoint.uri, ejection.ejection_ms
+            );
+        }
+    }
+
+    fn record_health_check(
+        &self,
+        endpoint: &Endpoint,
+        health_check: &config::HealthCheck,
+        ok: bool,
+    ) {
+        let healthy = endpoint.healthy.load(Ordering::Relaxed);
+        if ok == healthy {
+            endpoint.health_check_streak.store(0, Ordering::Relaxed);
+            return;
+        }
+
+        let streak = endpoint.health_check_streak.fetch_add(1, Ordering::Relaxed) + 1;
+        let threshold = if ok {
+            health_check.healthy_threshold
+        } else {
+            health_check.unhealthy_threshold
+        };
+        if streak >= threshold.max(1) {
+            endpoint.health_check_streak.store(0, Ordering::Relaxed);
+            endpoint.healthy.store(ok, Ordering::Relaxed);
+            if ok {
+                endpoint
+                    .available_since_ms
+                    .store(utils::get_current_unix_timestamp_ms(), Ordering::Relaxed);
+            }
+            println!(
+                "Upstream endpoint {} is now {}",
+                endpoint.uri,
+                if ok { "healthy" } else { "unhealthy" }
+            );
+        }
+    }
+
+    async fn check_endpoint(
+        client: &HttpClient,
+        endpoint: &Endpoint,
+        health_check: &config::HealthCheck,
+    ) -> bool {
+        let uri = match Uri::builder()
+            .scheme(endpoint.uri.scheme_str().unwrap_or("http"))
+            .authority(
+                endpoint
+                    .uri
+                    .authority()
+                    .map_or("", |authority| authority.as_str()),
+            )
+            .path_and_query(health_check.path.as_str())
+            .build()
+        {
+            Ok(uri) => uri,
+            Err(_) => return false,
+        };
+        let req = match Request::get(uri).body(Full::new(Bytes::new())) {
+            Ok(req) => req,
+            Err(_) => return false,
+        };
+
+        matches!(
+            tokio::time::timeout(Duration::from_millis(health_check.timeout_ms), client.request(req)).await,
+            Ok(Ok(response)) if response.status().is_success()
+        )
+    }
+
+    /// Runs the active health checks in the background when the pool has any configured.
+    pub fn start_health_checks(self: &Arc<Self>, client: HttpClient) {
+        let Some(health_check) = self.config.health_check.clone() else {
+            return;
+        };
+
+        let pool = self.clone();
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(Duration::from_millis(health_check.interval_ms.max(1)));
+            loop {
+                interval.tick().await;
+                for endpoint in pool.endpoints.iter() {
+                    let ok = Pool::check_endpoint(&client, endpoint, &health_check).await;
+                    pool.record_health_check(endpoint, &health_check, ok);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(strategy: Strategy) -> Pool {
+        Pool::new(
+            "test",
+            config::UpstreamPool {
+                endpoints: vec![
+                    String::from("http://10.0.0.1:8080"),
+                    String::from("http://10.0.0.2:8080"),
+                    String::from("http://10.0.0.3:8080"),
+                ],
+                strategy,
+                passive_ejection: config::PassiveEjection {
+                    consecutive_failures: 2,
+                    ejection_ms: 60000,
+                },
+                ..Default::default()
+            },
+            None,
+            None,
+            None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_consistent_hash_is_sticky() {
+        let pool = pool(Strategy::ConsistentHash);
+        let first = pool.pick(Some("user-1")).unwrap().endpoint.uri.clone();
+        for _ in 0..10 {
+            assert_eq!(pool.pick(Some("user-1")).unwrap().endpoint.uri, first);
+        }
+    }
+
+    #[test]
+    fn test_least_connections() {
+        let pool = pool(Strategy::LeastConnections);
+        let a = pool.pick(None).unwrap();
+        let b = pool.pick(None).unwrap();
+        let c = pool.pick(None).unwrap();
+        assert_ne!(a.endpoint.uri, b.endpoint.uri);
+        assert_ne!(b.endpoint.uri, c.endpoint.uri);
+        assert_ne!(a.endpoint.uri, c.endpoint.uri);
+
+        let released = b.endpoint.uri.clone();
+        drop(b);
+        assert_eq!(pool.pick(None).unwrap().endpoint.uri, released);
+    }
+
+    #[test]
+    fn test_passive_ejection() {
+        let pool = pool(Strategy::RoundRobin);
+        let ejected = pool.endpoints()[0].clone();
+        pool.report(&ejected, false);
+        pool.report(&ejected, false);
+
+        for _ in 0..10 {
+            assert_ne!(pool.pick(None).unwrap().endpoint.uri, ejected.uri);
+        }
+    }
+}
@@ -0,0 +1,282 @@
+use http_body_util::Full;
+use hyper::{
+    body::Bytes,
+    header::{self, HeaderName, HeaderValue},
+    HeaderMap, Response, StatusCode,
+};
+use std::{
+    collections::HashMap,
+    hash::{BuildHasher, RandomState},
+    sync::{Mutex, MutexGuard},
+    time::Instant,
+};
+
+use crate::{config, permission::PermissionSet, utils};
+
+const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+
+// keys hash to one of the shards, each with its own lock
+const SHARDS: usize = 64;
+// buckets kept at most, a shard that is full drops idle buckets, then the least recently used
+const MAX_BUCKETS: usize = 100_000;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    limit: config::Limit,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.rate).min(self.limit.burst as f64);
+        // a caller that took `now` before the lock does not make the bucket look older
+        self.updated = self.updated.max(now);
+    }
+
+    fn is_full(&self) -> bool {
+        self.tokens >= self.limit.burst as f64
+    }
+}
+
+#[derive(Debug)]
+pub struct Decision {
+    pub allowed: bool,
+    limit: u64,
+    remaining: u64,
+    // seconds until the bucket is full again
+    reset: u64,
+    // seconds until the next token, only when not allowed
+    retry_after: u64,
+}
+
+impl Decision {
+    pub fn set_headers(&self, headers: &mut HeaderMap) {
+        headers.insert(RATELIMIT_LIMIT, HeaderValue::from(self.limit));
+        headers.insert(RATELIMIT_REMAINING, HeaderValue::from(self.remaining));
+        headers.insert(RATELIMIT_RESET, HeaderValue::from(self.reset));
+    }
+
+    pub fn response(&self) -> Response<Full<Bytes>> {
+        let mut response =
+            utils::status_response(StatusCode::TOO_MANY_REQUESTS, "Too Many Requests");
+        self.set_headers(response.headers_mut());
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(self.retry_after));
+        response
+    }
+}
+
+type Shard = HashMap<String, Bucket>;
+
+// makes room for a new key, only runs once the shard is at its share of the buckets
+fn evict(shard: &mut Shard, per_shard: usize, now: Instant) {
+    // a bucket that refilled is the same as a new one, dropping it loses nothing
+    shard.retain(|_, bucket| {
+        bucket.refill(now);
+        !bucket.is_full()
+    });
+    if shard.len() < per_shard {
+        return;
+    }
+    let oldest = shard
+        .iter()
+        .min_by_key(|(_, bucket)| bucket.updated)
+        .map(|(key, _)| key.clone());
+    if let Some(oldest) = oldest {
+        shard.remove(&oldest);
+    }
+}
+
+/// Token buckets keyed by whatever is being limited (a user's `sub`, a client ip, ...), spread
+/// over shards and bounded by `MAX_BUCKETS`.
+#[derive(Debug)]
+pub struct RateLimiter {
+    shards: Vec<Mutex<Shard>>,
+    per_shard: usize,
+    // random per limiter so clients can not aim their keys at one shard
+    hasher: RandomState,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        RateLimiter::with_max_buckets(MAX_BUCKETS)
+    }
+
+    fn with_max_buckets(max_buckets: usize) -> Self {
+        RateLimiter {
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            per_shard: (max_buckets / SHARDS).max(1),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard(&self, key: &str) -> MutexGuard<'_, Shard> {
+        let index = self.hasher.hash_one(key) as usize % SHARDS;
+        self.shards[index].lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Buckets currently kept, for the metrics.
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap_or_else(|e| e.into_inner()).len())
+            .sum()
+    }
+
+    pub fn check(&self, key: &str, limit: &config::Limit) -> Decision {
+        self.check_at(key, limit, Instant::now())
+    }
+
+    fn check_at(&self, key: &str, limit: &config::Limit, now: Instant) -> Decision {
+        let mut shard = self.shard(key);
+
+        if !shard.contains_key(key) && shard.len() >= self.per_shard {
+            evict(&mut shard, self.per_shard, now);
+        }
+
+        let bucket = shard.entry(key.to_string()).or_insert_with(|| Bucket {
+            tokens: limit.burst as f64,
+            updated: now,
+            limit: limit.clone(),
+        });
+        bucket.refill(now);
+        // the configured limit is the one that counts, e.g. after a user's tier changed
+        bucket.limit = limit.clone();
+        bucket.tokens = bucket.tokens.min(limit.burst as f64);
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+
+        let seconds_for = |tokens: f64| {
+            if limit.rate > 0.0 {
+                (tokens / limit.rate).ceil() as u64
+            } else {
+                u64::MAX
+            }
+        };
+
+        Decision {
+            allowed,
+            limit: limit.burst,
+            remaining: bucket.tokens.floor() as u64,
+            reset: seconds_for(limit.burst as f64 - bucket.tokens),
+            retry_after: if allowed {
+                0
+            } else {
+                seconds_for(1.0 - bucket.tokens).max(1)
+            },
+        }
+    }
+}
+
+/// The limit that applies to a user, the first tier whose permission they hold wins.
+pub fn limit_for<'a>(
+    rate_limit: &'a config::RateLimit,
+    permissions: &PermissionSet,
+) -> Option<&'a config::Limit> {
+    rate_limit
+        .tiers
+        .iter()
+        .find(|tier| permissions.holds(&tier.permission))
+        .map(|tier| &tier.limit)
+        .or(rate_limit.default.as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::new();
+        let limit = config::Limit {
+            rate: 1.0,
+            burst: 2,
+        };
+        let now = Instant::now();
+
+        assert!(limiter.check_at("user", &limit, now).allowed);
+        assert!(limiter.check_at("user", &limit, now).allowed);
+        let decision = limiter.check_at("user", &limit, now);
+        assert!(!decision.allowed);
+        assert_eq!(decision.retry_after, 1);
+
+        // other keys have their own bucket
+        assert!(limiter.check_at("other", &limit, now).allowed);
+
+        assert!(
+            limiter
+                .check_at("user", &limit, now + Duration::from_secs(1))
+                .allowed
+        );
+    }
+
+    #[test]
+    fn test_buckets_are_bounded() {
+        let max_buckets = SHARDS * 8;
+        let limiter = RateLimiter::with_max_buckets(max_buckets);
+        let limit = config::Limit {
+            rate: 0.001,
+            burst: 2,
+        };
+        let now = Instant::now();
+
+        // e.g. rotating client ips, none of the buckets refills in time to be dropped as idle
+        for ip in 0..max_buckets * 4 {
+            limiter.check_at(&format!("ip:{}", ip), &limit, now);
+        }
+        assert!(limiter.len() <= max_buckets);
+
+        // the least recently used go first, a user who keeps sending stays limited
+        let later = now + Duration::from_secs(1);
+        limiter.check_at("user", &limit, later);
+        limiter.check_at("user", &limit, later);
+        for ip in 0..max_buckets * 4 {
+            limiter.check_at(&format!("new:{}", ip), &limit, later);
+            limiter.check_at("user", &limit, later + Duration::from_secs(1));
+        }
+        let decision = limiter.check_at("user", &limit, later + Duration::from_secs(1));
+        assert!(!decision.allowed);
+    }
+
+    #[test]
+    fn test_limit_for() {
+        let premium = config::Limit {
+            rate: 100.0,
+            burst: 200,
+        };
+        let default = config::Limit {
+            rate: 1.0,
+            burst: 2,
+        };
+        let rate_limit = config::RateLimit {
+            default: Some(default),
+            tiers: vec![config::Tier {
+                permission: "premium".into(),
+                limit: premium,
+            }],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            limit_for(&rate_limit, &PermissionSet::new(["news", "premium"]))
+                .unwrap()
+                .burst,
+            200
+        );
+        assert_eq!(
+            limit_for(&rate_limit, &PermissionSet::new(["news"]))
+                .unwrap()
+                .burst,
+            2
+        );
+    }
+}
@@ -1,160 +1,473 @@
 use anyhow::{anyhow, Result};
 use http_body_util::{BodyExt, Full};
-use hyper::{body::Bytes, Request, Response, Uri};
-use hyper_util::client::legacy::connect::HttpConnector;
+use hyper::{body::Bytes, header::HeaderValue, Request, Response, StatusCode, Uri};
 use std::{
-    sync::{Arc, RwLock},
-    time::Duration,
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc},
 };
-use tokio::time::timeout;
-use tokio_tungstenite::tungstenite::http::uri::Scheme;
-
-use crate::{config, session::Session, sessions, socket, user, utils};
-
-async fn set_timer(session: Arc<RwLock<Session>>, active_sessions: Arc<sessions::SafeSessions>) {
-    let timeout = timeout(
-        match session.clone().read() {
-            Ok(session) => Duration::from_secs(
-                session.get_access_jwt().get_payload().exp - utils::get_current_unix_timestamp(),
-            ),
-            Err(_) => {
-                eprintln!("Error reading session");
-                Duration::from_secs(0)
-            }
-        },
-        async move {
-            match session.read() {
-                Ok(session) => match active_sessions.get(&session) {
-                    Ok(Some(set)) => {
-                        if let Ok(session) = set.read() {
-                            if session.get_access_jwt().is_expired() {
-                                if let Some(socket_session) = session.get_socket_session() {
-                                    socket_session
-                                        .transmitter
-                                        .send("Session expired".to_string())
-                                        .unwrap();
-                                    match active_sessions.remove(&session) {
-                                        Ok(_) => (),
-                                        Err(_) => eprintln!("Error removing session"),
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    Ok(None) => (),
-                    Err(e) => eprintln!("Error getting session: {:?}", e),
-                },
-                Err(e) => eprintln!("Error reading session: {:?}", e),
-            }
-        },
+
+use crate::{
+    circuit_breaker::CircuitOpen,
+    config::{self, AuthMode},
+    forwarding, login, peers,
+    permission::{self, PermissionSet, Permissions},
+    rate_limit, routes, scheduler,
+    session::{Session, SharedSession},
+    socket,
+    state::State,
+    sweeper, upstream, utils,
+};
+
+// while degraded the provider is asked again this often
+const DEGRADED_RETRY_SECS: u64 = 5;
+
+async fn get_user_permissions(session: &Session, state: &State) -> Result<Permissions> {
+    state.permission_provider.get_permissions(session).await
+}
+
+// gives the session the permissions the provider last gave `last` when the provider is
+// unavailable, `last` is the same session as it was before the lookup and still in its grace
+fn degrade(
+    session: &mut Session,
+    last: &Session,
+    degraded_mode: &config::DegradedMode,
+    err: &anyhow::Error,
+    now_ms: u64,
+) -> bool {
+    let fetched_at_ms = last.get_permissions_fetched_at().saturating_mul(1000);
+    if !permission::is_unavailable(err)
+        || last.get_refresh_jwt().get_full_token() != session.get_refresh_jwt().get_full_token()
+        || fetched_at_ms == 0
+        || fetched_at_ms.saturating_add(degraded_mode.grace_ms) < now_ms
+    {
+        return false;
+    }
+    session.degrade_from(
+        last,
+        degraded_mode.safe_permissions.as_deref(),
+        now_ms / 1000 + DEGRADED_RETRY_SECS,
+    );
+    true
+}
+
+/// Keeps the session going on the permissions the provider last gave it while the provider is
+/// unavailable, a refusal or a session it never approved is not covered.
+pub fn fall_back(
+    session: &mut Session,
+    current: Option<&Arc<SharedSession>>,
+    state: &State,
+    err: anyhow::Error,
+) -> Result<()> {
+    let (Some(degraded_mode), Some(current)) = (&state.config.degraded_mode, current) else {
+        return Err(err);
+    };
+    let now_ms = utils::get_current_unix_timestamp_ms();
+    if !degrade(session, &current.load(), degraded_mode, &err, now_ms) {
+        return Err(err);
+    }
+    eprintln!(
+        "Error getting permissions for {}, using the last known ones: {:#}",
+        session.get_access_jwt().get_payload().sub,
+        err
     );
+    state.permission_fallbacks.fetch_add(1, Ordering::Relaxed);
+    Ok(())
+}
 
-    tokio::spawn(async move {
-        match timeout.await {
-            Ok(_) => (),
-            Err(_) => eprintln!("Error setting timer"),
+async fn fetch_permissions(
+    session: &mut Session,
+    current: Option<&Arc<SharedSession>>,
+    state: &State,
+) -> Result<()> {
+    match get_user_permissions(session, state).await {
+        Ok(permissions) => {
+            session.apply_permissions(permissions);
+            Ok(())
         }
-    });
+        Err(err) => fall_back(session, current, state, err),
+    }
 }
 
-pub async fn handle_request(
+async fn get_session(mut session: Session, state: &Arc<State>) -> Result<Arc<SharedSession>> {
+    let active_sessions = &state.sessions;
+    let session = match active_sessions.get(&session) {
+        None => {
+            fetch_permissions(&mut session, None, state).await?;
+            let session = active_sessions.insert(session);
+            sweeper::enforce_capacity(state);
+            scheduler::watch(state, &session);
+            peers::publish(state, &session);
+            session
+        }
+        Some(cur_session) => {
+            let base = cur_session.load();
+            if base.get_access_jwt().is_expired() || base.permissions_expired() {
+                fetch_permissions(&mut session, Some(&cur_session), state).await?;
+                let session = active_sessions.update(session, &base);
+                scheduler::watch(state, &session);
+                peers::publish(state, &session);
+                session
+            } else {
+                cur_session.touch();
+                cur_session
+            }
+        }
+    };
+    Ok(session)
+}
+
+async fn forward_request(
     req: Request<hyper::body::Incoming>,
-    active_sessions: Arc<sessions::SafeSessions>,
-    config: Arc<config::Config>,
-    client: hyper_util::client::legacy::Client<HttpConnector, Full<Bytes>>,
+    remote_addr: SocketAddr,
+    route: Option<&config::Route>,
+    sub: Option<&str>,
+    permissions: &PermissionSet,
+    config: &Arc<config::Config>,
+    upstream: &upstream::Upstream,
+) -> Result<Response<Full<Bytes>>> {
+    // the scheme and authority are filled in by the upstream endpoint
+    let new_uri = Uri::builder()
+        .path_and_query(forwarding::upstream_path_and_query(
+            req.uri(),
+            permissions.forwarded(),
+        ))
+        .build()?;
+
+    let (mut parts, body) = req.into_parts();
+    parts.uri = new_uri;
+    forwarding::remove_hop_by_hop_headers(&mut parts.headers);
+    forwarding::set_forwarding_headers(&mut parts.headers, &remote_addr, &config.trusted_proxies);
+    let body = body.collect().await?.to_bytes();
+    let req = Request::from_parts(parts, Full::from(body));
+
+    let (mut parts, body) = upstream
+        .send(req, route, sub, permissions)
+        .await
+        .into_parts();
+    forwarding::remove_hop_by_hop_headers(&mut parts.headers);
+    Ok(Response::from_parts(parts, body))
+}
+
+fn forbidden(
+    route: Option<&config::Route>,
+    permissions: &PermissionSet,
+) -> Option<Response<Full<Bytes>>> {
+    route
+        .filter(|route| !route.allows(permissions))
+        .map(|_| utils::status_response(StatusCode::FORBIDDEN, "Forbidden"))
+}
+
+// `None` when the route does not limit this kind of client
+fn check_rate_limit(
+    state: &State,
+    route: Option<&config::Route>,
+    identity: &str,
+    limit: impl FnOnce(&config::RateLimit) -> Option<&config::Limit>,
+) -> Option<rate_limit::Decision> {
+    let route = route?;
+    let limit = limit(route.rate_limit.as_ref()?)?;
+    Some(
+        state
+            .rate_limiter
+            .check(&format!("{}|{}", route.path, identity), limit),
+    )
+}
+
+async fn route_request(
+    mut req: Request<hyper::body::Incoming>,
+    remote_addr: SocketAddr,
+    state: &Arc<State>,
 ) -> Result<Response<Full<Bytes>>> {
-    let cookies = utils::get_cookies(&req);
+    let config = &state.config;
+    let upstream = &state.upstream;
+    if !routes::is_plain_path(req.uri().path()) {
+        return Ok(utils::status_response(
+            StatusCode::BAD_REQUEST,
+            "Bad Request",
+        ));
+    }
+    let route = routes::find(&config.routes, req.method(), req.uri().path());
+    let auth = route.map_or(AuthMode::Required, |route| route.auth);
+    let is_upgrade = hyper_tungstenite::is_upgrade_request(&req);
+
+    // signed by the permission service instead of carrying a session
+    if let Some(webhook) = state
+        .webhook
+        .as_ref()
+        .filter(|webhook| webhook.matches(req.method(), req.uri().path()))
+    {
+        return webhook.handle(req, state).await;
+    }
+
+    if let Some(logout) = state
+        .logout
+        .as_ref()
+        .filter(|logout| logout.matches(req.method(), req.uri().path()))
+    {
+        return logout.handle(req, state).await;
+    }
+
+    // only ever set by the gateway
+    req.headers_mut().remove(forwarding::X_PERMISSION_METADATA);
+    req.headers_mut().remove(forwarding::X_PERMISSIONS_DEGRADED);
 
     // get access tocken from cookies
-    let mut session = Session::from_cookies(
-        cookies,
+    let session = Session::from_cookies(
+        utils::get_cookies(&req),
         &config.access_token_jwt_cookie_name,
         &config.refresh_token_jwt_cookie_name,
-    )?;
+    )
+    // a logged out refresh token does not start a new session
+    .and_then(|session| {
+        if state
+            .sessions
+            .is_ended(session.get_refresh_jwt().get_full_token())
+        {
+            return Err(anyhow!("session ended"));
+        }
+        Ok(session)
+    });
 
-    let session = match active_sessions.get(&session)? {
-        None => {
-            let permissions = user::get_user_permissions(&session, &config).await?;
-            session.set_permissions(permissions);
-            let session = active_sessions.insert(session)?;
-            set_timer(session.clone(), active_sessions.clone()).await;
-            session
+    let session = match (auth, session) {
+        (AuthMode::Public, _) | (AuthMode::Optional, Err(_)) if !is_upgrade => {
+            let permissions = &state.anonymous_permissions;
+            if let Some(response) = forbidden(route, permissions) {
+                return Ok(response);
+            }
+
+            let ip = forwarding::client_ip(req.headers(), &remote_addr, &config.trusted_proxies);
+            let decision = check_rate_limit(state, route, &format!("ip:{}", ip), |rate_limit| {
+                rate_limit.anonymous.as_ref()
+            });
+            if let Some(decision) = decision.as_ref().filter(|decision| !decision.allowed) {
+                return Ok(decision.response());
+            }
+
+            let mut response =
+                forward_request(req, remote_addr, route, None, permissions, config, upstream)
+                    .await?;
+            if let Some(decision) = decision {
+                decision.set_headers(response.headers_mut());
+            }
+            return Ok(response);
         }
-        Some(cur_session) => {
-            if cur_session
-                .read()
-                .map_err(|_| anyhow!("Session mismatch"))?
-                .get_access_jwt()
-                .is_expired()
+        (_, Ok(session)) => session,
+        (_, Err(err)) => {
+            if let Some(response) = config
+                .login_redirect
+                .as_ref()
+                .and_then(|login| login::redirect_response(&req, login))
             {
-                let permissions = user::get_user_permissions(&session, &config).await?;
-                session.set_permissions(permissions);
-                let session = active_sessions.update(session)?.clone();
-                set_timer(session.clone(), active_sessions.clone()).await;
-                session
-            } else {
-                cur_session.clone()
+                return Ok(response);
+            }
+            return Ok(utils::status_response(
+                StatusCode::UNAUTHORIZED,
+                &err.to_string(),
+            ));
+        }
+    };
+
+    let session = get_session(session, state).await?;
+
+    let (sub, permissions) = {
+        let session = session.load();
+        let metadata = session.get_permission_metadata();
+        if !metadata.is_empty() {
+            match HeaderValue::from_str(&serde_json::to_string(metadata)?) {
+                Ok(metadata) => {
+                    req.headers_mut()
+                        .insert(forwarding::X_PERMISSION_METADATA, metadata);
+                }
+                // e.g. non ascii text, the request goes through without it
+                Err(err) => eprintln!("Error encoding permission metadata: {:?}", err),
             }
         }
+        if session.is_degraded() {
+            state.degraded_requests.fetch_add(1, Ordering::Relaxed);
+            req.headers_mut().insert(
+                forwarding::X_PERMISSIONS_DEGRADED,
+                HeaderValue::from_static("1"),
+            );
+        }
+        (
+            session.get_access_jwt().get_payload().sub.clone(),
+            session.get_permissions(),
+        )
     };
+    if let Some(response) = forbidden(route, &permissions) {
+        return Ok(response);
+    }
 
-    if hyper_tungstenite::is_upgrade_request(&req) {
-        socket::web_socket::handle_web_socket(req, &active_sessions, &config).await
+    let decision = check_rate_limit(state, route, &format!("sub:{}", sub), |rate_limit| {
+        rate_limit::limit_for(rate_limit, &permissions)
+    });
+    if let Some(decision) = decision.as_ref().filter(|decision| !decision.allowed) {
+        return Ok(decision.response());
+    }
+
+    let mut response = if is_upgrade {
+        socket::web_socket::handle_web_socket(req, state).await?
     } else {
         // Handle non-WebSocket requests
 
         match (req.method(), req.uri().path()) {
             // Create Key Request
             (&hyper::Method::GET, "/get_websocket_key") => {
-                socket::gen_socket_key::gen_socket_key(&session, &config)
+                socket::gen_socket_key::gen_socket_key(&session, state)?
             }
 
             (&hyper::Method::GET, "/socket_keep_alive") => {
-                socket::gen_socket_key::gen_socket_key(&session, &config)
+                socket::gen_socket_key::gen_socket_key(&session, state)?
             }
 
             (_, _) => {
-                let old_uri = req.uri().clone();
-                let old_path_and_query = old_uri.path_and_query().unwrap().clone();
-
-                let path = old_path_and_query.path();
-                let mut query = String::from(old_path_and_query.query().unwrap_or_default());
-                query.push_str(&format!(
-                    "permissions={}",
-                    session
-                        .read()
-                        .unwrap()
-                        .get_permissions()
-                        .iter()
-                        .map(|arc_str| arc_str.as_str())
-                        .collect::<Vec<&str>>()
-                        .join(",")
-                ));
-
-                let new_uri = Uri::builder()
-                    .scheme(config.sidecar_url.scheme().unwrap_or(&Scheme::HTTP).clone())
-                    .authority(config.sidecar_url.authority().unwrap().clone())
-                    .path_and_query(format!("{}?{}", path, query))
-                    .build()
-                    .unwrap();
-
-                let (mut parts, body) = req.into_parts();
-                parts.uri = new_uri.clone();
-                let body = body.collect().await?.to_bytes();
-                let req = Request::from_parts(parts, Full::from(body));
-
-                match client.request(req).await {
-                    Ok(response) => {
-                        let (parts, body) = response.into_parts();
-                        let body = body.collect().await?.to_bytes();
-                        Ok(Response::from_parts(parts, Full::from(body)))
-                    }
-                    Err(err) => {
-                        println!("Error forwarding request: {err:?}");
-                        return Err(anyhow!(err));
-                    }
-                }
+                forward_request(
+                    req,
+                    remote_addr,
+                    route,
+                    Some(&sub),
+                    &permissions,
+                    config,
+                    upstream,
+                )
+                .await?
             }
         }
+    };
+
+    if let Some(decision) = decision {
+        decision.set_headers(response.headers_mut());
+    }
+    Ok(response)
+}
+
+pub async fn handle_request(
+    mut req: Request<hyper::body::Incoming>,
+    remote_addr: SocketAddr,
+    state: Arc<State>,
+) -> Result<Response<Full<Bytes>>> {
+    let request_id = forwarding::get_or_generate_request_id(req.headers());
+    req.headers_mut()
+        .insert(forwarding::X_REQUEST_ID, request_id.clone());
+
+    let mut response = match route_request(req, remote_addr, &state).await {
+        Ok(response) => response,
+        Err(err) => match err.downcast_ref::<CircuitOpen>() {
+            Some(open) => open.response(),
+            None => return Err(err),
+        },
+    };
+    response
+        .headers_mut()
+        .insert(forwarding::X_REQUEST_ID, request_id);
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit_breaker::CircuitBreaker;
+
+    fn approved(jti: &str) -> Session {
+        let mut session = Session::for_token("201944", "example.com", jti);
+        session.apply_permissions(Permissions {
+            permissions: vec![String::from("cta"), String::from("news")],
+            ..Default::default()
+        });
+        session
+    }
+
+    fn unavailable() -> anyhow::Error {
+        let breaker = CircuitBreaker::new(
+            "permission_service",
+            config::CircuitBreaker {
+                failure_threshold: 1,
+                open_ms: 60000,
+                ..Default::default()
+            },
+        );
+        drop(breaker.acquire().unwrap());
+        breaker.acquire().unwrap_err().into()
+    }
+
+    #[test]
+    fn test_degrade() {
+        let last = approved("jti");
+        let degraded_mode = config::DegradedMode {
+            grace_ms: 500,
+            safe_permissions: None,
+        };
+        let now_ms = last.get_permissions_fetched_at() * 1000;
+
+        let mut session = last.detached();
+        assert!(degrade(
+            &mut session,
+            &last,
+            &degraded_mode,
+            &unavailable(),
+            now_ms + 400
+        ));
+        assert!(session.is_degraded());
+        assert_eq!(session.get_permissions().forwarded(), "cta,news");
+
+        // a grace under a second still runs out
+        let mut session = last.detached();
+        assert!(!degrade(
+            &mut session,
+            &last,
+            &degraded_mode,
+            &unavailable(),
+            now_ms + 600
+        ));
+
+        // a refusal is not covered
+        let refused = anyhow::anyhow!("permission service answered 403 Forbidden");
+        assert!(!degrade(
+            &mut session,
+            &last,
+            &degraded_mode,
+            &refused,
+            now_ms
+        ));
+
+        // nor another session of the user
+        let mut other = Session::for_token("201944", "example.com", "other");
+        assert!(!degrade(
+            &mut other,
+            &last,
+            &degraded_mode,
+            &unavailable(),
+            now_ms
+        ));
+
+        // nor one the provider never answered for
+        let mut session = last.detached();
+        assert!(!degrade(
+            &mut session,
+            &last.detached(),
+            &degraded_mode,
+            &unavailable(),
+            now_ms
+        ));
+    }
+
+    #[test]
+    fn test_degrade_to_safe_permissions() {
+        let last = approved("jti");
+        let degraded_mode = config::DegradedMode {
+            grace_ms: 60000,
+            safe_permissions: Some(vec![String::from("news")]),
+        };
+        let now_ms = last.get_permissions_fetched_at() * 1000;
+
+        let mut session = last.detached();
+        assert!(degrade(
+            &mut session,
+            &last,
+            &degraded_mode,
+            &unavailable(),
+            now_ms
+        ));
+        assert_eq!(session.get_permissions().forwarded(), "news");
     }
 }
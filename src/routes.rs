@@ -0,0 +1,101 @@
+use hyper::Method;
+use percent_encoding::percent_decode_str;
+
+use crate::{config::Route, permission::PermissionSet};
+
+impl Route {
+    pub fn matches(&self, method: &Method, path: &str) -> bool {
+        let path_matches = match self.path.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == self.path,
+        };
+
+        path_matches
+            && (self.methods.is_empty()
+                || self
+                    .methods
+                    .iter()
+                    .any(|m| m.eq_ignore_ascii_case(method.as_str())))
+    }
+
+    // every required permission must be granted, see `PermissionSet::holds`
+    pub fn allows(&self, permissions: &PermissionSet) -> bool {
+        self.required_permissions
+            .iter()
+            .all(|required| permissions.holds(required))
+    }
+}
+
+/// Whether `path` names what it looks like. Routes match the path as sent while upstreams may
+/// resolve `.` and `..` segments, also when percent encoded, or decode an encoded `/`, so such
+/// a path could reach a route other than the one that matched.
+pub fn is_plain_path(path: &str) -> bool {
+    path.split('/').all(|segment| {
+        let decoded = percent_decode_str(segment).decode_utf8_lossy();
+        decoded != "." && decoded != ".." && !decoded.contains(['/', '\\'])
+    })
+}
+
+// the first route that matches wins
+pub fn find<'a>(routes: &'a [Route], method: &Method, path: &str) -> Option<&'a Route> {
+    routes.iter().find(|route| route.matches(method, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AuthMode;
+
+    fn route(path: &str, methods: &[&str], auth: AuthMode) -> Route {
+        Route {
+            path: path.to_string(),
+            methods: methods.iter().map(|m| m.to_string()).collect(),
+            auth,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_find_route() {
+        let routes = vec![
+            route("/healthz", &["GET"], AuthMode::Public),
+            route("/static/*", &[], AuthMode::Public),
+            route("/*", &["get"], AuthMode::Optional),
+        ];
+
+        let found = find(&routes, &Method::GET, "/healthz").unwrap();
+        assert_eq!(found.path, "/healthz");
+
+        let found = find(&routes, &Method::POST, "/static/app.js").unwrap();
+        assert_eq!(found.path, "/static/*");
+
+        let found = find(&routes, &Method::GET, "/healthz/deep").unwrap();
+        assert_eq!(found.auth, AuthMode::Optional);
+
+        assert!(find(&routes, &Method::POST, "/healthz").is_none());
+    }
+
+    #[test]
+    fn test_plain_paths() {
+        assert!(is_plain_path("/static/app.js"));
+        assert!(is_plain_path("/static/app..js"));
+        assert!(is_plain_path("/quotes/a%20b"));
+        assert!(!is_plain_path("/static/../admin/x"));
+        assert!(!is_plain_path("/static/%2e%2E/admin/x"));
+        assert!(!is_plain_path("/static/./app.js"));
+        assert!(!is_plain_path("/static/..%2fadmin"));
+        assert!(!is_plain_path("/static/..%5cadmin"));
+    }
+
+    #[test]
+    fn test_required_permissions() {
+        let route = Route {
+            required_permissions: vec!["nasdaq".into(), "cta".into()],
+            ..route("/quotes/*", &[], AuthMode::Required)
+        };
+
+        assert!(route.allows(&PermissionSet::new(["cta", "news", "nasdaq"])));
+        assert!(!route.allows(&PermissionSet::new(["nasdaq"])));
+        assert!(route.allows(&PermissionSet::new(["*"])));
+    }
+}
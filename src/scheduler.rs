@@ -0,0 +1,355 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use tokio::sync::Notify;
+
+use crate::{
+    peers, permission, request,
+    session::{Session, SharedSession, SocketEvent},
+    state::State,
+    sweeper, utils,
+};
+
+// while the provider fails a refresh is tried again this often
+const REFRESH_RETRY_SECS: u64 = 5;
+
+/// What comes due for a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Deadline {
+    // the access token expires, the session's websockets close
+    AccessExpiry,
+    // a grant runs out and is removed
+    PermissionExpiry,
+    // the provider's ttl ends, open websockets get their permissions looked up again
+    PermissionRefresh,
+    // the refresh token expires, the session is dropped
+    SessionExpiry,
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    // unix seconds, the session's refresh token and what is due, earliest first
+    due: BTreeSet<(u64, Arc<str>, Deadline)>,
+    // what is scheduled for each session, to reschedule and cancel
+    sessions: HashMap<Arc<str>, BTreeMap<Deadline, u64>>,
+}
+
+impl Queue {
+    // true when `at` is now the earliest deadline
+    fn schedule(&mut self, key: &str, deadline: Deadline, at: u64) -> bool {
+        // the key is shared by the session's deadlines
+        let key = match self.sessions.get_key_value(key) {
+            Some((key, _)) => key.clone(),
+            None => Arc::from(key),
+        };
+        let scheduled = self.sessions.entry(key.clone()).or_default();
+        if let Some(previous) = scheduled.insert(deadline, at) {
+            if previous == at {
+                return false;
+            }
+            self.due.remove(&(previous, key.clone(), deadline));
+        }
+        self.due.insert((at, key, deadline));
+        self.next() == Some(at)
+    }
+
+    fn cancel(&mut self, key: &str, deadline: Deadline) {
+        let Some(scheduled) = self.sessions.get_mut(key) else {
+            return;
+        };
+        if let Some(at) = scheduled.remove(&deadline) {
+            self.due.remove(&(at, Arc::from(key), deadline));
+        }
+        if scheduled.is_empty() {
+            self.sessions.remove(key);
+        }
+    }
+
+    fn cancel_session(&mut self, key: &str) {
+        let Some((key, scheduled)) = self.sessions.remove_entry(key) else {
+            return;
+        };
+        for (deadline, at) in scheduled {
+            self.due.remove(&(at, key.clone(), deadline));
+        }
+    }
+
+    // everything due at `now`, including deadlines that had passed when they were scheduled
+    fn take_due(&mut self, now: u64) -> Vec<(Arc<str>, Deadline)> {
+        let mut taken = Vec::new();
+        while let Some((at, key, deadline)) = self.due.first().cloned() {
+            if at > now {
+                break;
+            }
+            self.due.pop_first();
+            if let Some(scheduled) = self.sessions.get_mut(&key) {
+                scheduled.remove(&deadline);
+                if scheduled.is_empty() {
+                    self.sessions.remove(&key);
+                }
+            }
+            taken.push((key, deadline));
+        }
+        taken
+    }
+
+    fn next(&self) -> Option<u64> {
+        self.due.first().map(|(at, _, _)| *at)
+    }
+}
+
+/// Every session deadline in one queue, driven by a single task, see `start`. Sessions are
+/// keyed by their refresh token, scheduling a deadline again replaces the earlier one.
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    queue: Mutex<Queue>,
+    // wakes the task when a deadline earlier than the one it sleeps until is scheduled
+    wake: Notify,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler::default()
+    }
+
+    fn queue(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Runs `deadline` for the session at `at` unix seconds, right away when it passed.
+    pub fn schedule(&self, key: &str, deadline: Deadline, at: u64) {
+        if self.queue().schedule(key, deadline, at) {
+            self.wake.notify_one();
+        }
+    }
+
+    pub fn cancel(&self, key: &str, deadline: Deadline) {
+        self.queue().cancel(key, deadline);
+    }
+
+    /// Drops everything scheduled for a session that ended.
+    pub fn cancel_session(&self, key: &str) {
+        self.queue().cancel_session(key);
+    }
+
+    /// Deadlines waiting, for the metrics.
+    pub fn len(&self) -> usize {
+        self.queue().due.len()
+    }
+}
+
+/// Schedules the session's token expiries, its next grant expiry and its permission refresh,
+/// replacing what was scheduled for it before.
+pub fn watch(state: &State, session: &SharedSession) {
+    let session = session.load();
+    let scheduler = &state.scheduler;
+    let key = session.get_refresh_jwt().get_full_token();
+    // tokens count as expired the second after `exp`
+    scheduler.schedule(
+        key,
+        Deadline::AccessExpiry,
+        session.get_access_jwt().get_payload().exp.saturating_add(1),
+    );
+    scheduler.schedule(
+        key,
+        Deadline::SessionExpiry,
+        session
+            .get_refresh_jwt()
+            .get_payload()
+            .exp
+            .saturating_add(1),
+    );
+    match session.next_permission_expiry() {
+        Some(at) => scheduler.schedule(key, Deadline::PermissionExpiry, at),
+        None => scheduler.cancel(key, Deadline::PermissionExpiry),
+    }
+    match session.get_permissions_expire_at() {
+        Some(at) => scheduler.schedule(key, Deadline::PermissionRefresh, at),
+        None => scheduler.cancel(key, Deadline::PermissionRefresh),
+    }
+}
+
+// the lookup started from `base`, merged so a change pushed meanwhile is kept
+fn take_refresh(session: &SharedSession, base: &Session, lookup: &Session) {
+    session.update(|current| current.merge_fetched(lookup, base));
+}
+
+// requests look permissions up themselves when they run out, only open websockets need this
+fn refresh_permissions(state: &Arc<State>, key: &str, session: Arc<SharedSession>) {
+    let base = session.load();
+    if !base.has_open_sockets() || base.get_access_jwt().is_expired() {
+        return;
+    }
+    let mut lookup = base.detached();
+
+    let state = state.clone();
+    let key = key.to_string();
+    tokio::spawn(async move {
+        let result = match state.permission_provider.get_permissions(&lookup).await {
+            Ok(permissions) => {
+                lookup.apply_permissions(permissions);
+                Ok(())
+            }
+            Err(err) => request::fall_back(&mut lookup, Some(&session), &state, err),
+        };
+        match result {
+            Ok(()) => {
+                take_refresh(&session, &base, &lookup);
+                session
+                    .load()
+                    .notify_sockets(SocketEvent::PermissionsChanged);
+                watch(&state, &session);
+                peers::publish(&state, &session);
+            }
+            // without a degraded mode the websockets keep their permissions until the provider
+            // answers again
+            Err(err)
+                if permission::is_unavailable(&err) && state.config.degraded_mode.is_none() =>
+            {
+                eprintln!("Error refreshing permissions: {:#}", err);
+                state.scheduler.schedule(
+                    &key,
+                    Deadline::PermissionRefresh,
+                    utils::get_current_unix_timestamp() + REFRESH_RETRY_SECS,
+                );
+            }
+            // refused, or past the degraded grace, the same as a request would be
+            Err(err) => {
+                eprintln!(
+                    "Error refreshing permissions, closing the websockets: {:#}",
+                    err
+                );
+                session
+                    .load()
+                    .notify_sockets(SocketEvent::Close("Permissions unavailable".to_string()));
+            }
+        }
+    });
+}
+
+fn run(state: &Arc<State>, key: &str, deadline: Deadline) {
+    if deadline == Deadline::SessionExpiry {
+        let evicted = state.sessions.remove_expired(key);
+        if !evicted.is_empty() {
+            let state = state.clone();
+            tokio::spawn(async move { sweeper::end_sessions(&state, evicted).await });
+        }
+        return;
+    }
+
+    let Some(session) = state.sessions.get_by_token(key) else {
+        return;
+    };
+    let now = utils::get_current_unix_timestamp();
+    match deadline {
+        // a refreshed access token is swapped into the same session and shows up here
+        Deadline::AccessExpiry => {
+            let session = session.load();
+            if session.get_access_jwt().is_expired() {
+                session.notify_sockets(SocketEvent::Close("Session expired".to_string()));
+            }
+        }
+        Deadline::PermissionExpiry => {
+            let mut removed = false;
+            session.update(|session| removed = session.remove_expired_permissions(now));
+            if removed {
+                session
+                    .load()
+                    .notify_sockets(SocketEvent::PermissionsChanged);
+            }
+            watch(state, &session);
+        }
+        Deadline::PermissionRefresh => refresh_permissions(state, key, session),
+        Deadline::SessionExpiry => (),
+    }
+}
+
+/// Runs deadlines as they come due.
+pub fn start(state: Arc<State>) {
+    tokio::spawn(async move {
+        loop {
+            let due = state
+                .scheduler
+                .queue()
+                .take_due(utils::get_current_unix_timestamp());
+            // the queue is not locked while deadlines run, they schedule again
+            for (key, deadline) in &due {
+                run(&state, key, *deadline);
+            }
+            if !due.is_empty() {
+                continue;
+            }
+
+            let next = state.scheduler.queue().next();
+            let wake = state.scheduler.wake.notified();
+            match next {
+                Some(at) => {
+                    let delay = at
+                        .saturating_mul(1000)
+                        .saturating_sub(utils::get_current_unix_timestamp_ms());
+                    tokio::select! {
+                        _ = tokio::time::sleep(Duration::from_millis(delay)) => (),
+                        _ = wake => (),
+                    }
+                }
+                None => wake.await,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::permission::Permissions;
+
+    #[test]
+    fn test_queue() {
+        let mut queue = Queue::default();
+        assert!(queue.schedule("a", Deadline::AccessExpiry, 100));
+        assert!(!queue.schedule("b", Deadline::AccessExpiry, 200));
+        assert!(queue.schedule("b", Deadline::SessionExpiry, 50));
+
+        // rescheduling replaces the earlier deadline
+        assert!(!queue.schedule("a", Deadline::AccessExpiry, 300));
+        assert_eq!(
+            queue.take_due(150),
+            vec![(Arc::from("b"), Deadline::SessionExpiry)]
+        );
+        assert!(queue.take_due(150).is_empty());
+
+        queue.cancel("b", Deadline::AccessExpiry);
+        assert_eq!(queue.next(), Some(300));
+        queue.cancel_session("a");
+        assert_eq!(queue.next(), None);
+        assert!(queue.sessions.is_empty());
+
+        // deadlines that already passed are due right away
+        queue.schedule("c", Deadline::PermissionExpiry, 10);
+        assert_eq!(queue.take_due(150).len(), 1);
+    }
+
+    #[test]
+    fn test_push_during_refresh_survives() {
+        let session = SharedSession::new(Session::for_sub("201944"));
+        let base = session.load();
+        let mut lookup = base.detached();
+
+        // pushed while the refresh waits on the provider
+        session.update(|session| session.set_permissions(vec![String::from("nasdaq")]));
+        lookup.apply_permissions(Permissions {
+            permissions: vec![String::from("cta")],
+            ..Default::default()
+        });
+        take_refresh(&session, &base, &lookup);
+        assert_eq!(session.load().get_permissions().forwarded(), "nasdaq");
+
+        // with nothing in between the answer is taken
+        let base = session.load();
+        take_refresh(&session, &base, &lookup);
+        assert_eq!(session.load().get_permissions().forwarded(), "cta");
+    }
+}
@@ -1,37 +1,90 @@
 use anyhow::{anyhow, Result};
+use arc_swap::ArcSwap;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::sync::RwLock;
 
-use crate::jwt::Jwt;
+use crate::{
+    config::Permission,
+    jwt::Jwt,
+    permission::{Metadata, PermissionSet, Permissions},
+    utils,
+};
+
+/// Sent to the open websockets of a session.
+#[derive(Debug, Clone)]
+pub enum SocketEvent {
+    // closes the sockets with the reason
+    Close(String),
+    // the sockets reconnect upstream with the new permissions
+    PermissionsChanged,
+}
 
 #[derive(Debug)]
 pub struct SocketSession {
     pub uuid: String,
     pub hash: String,
-    pub transmitter: tokio::sync::broadcast::Sender<String>,
+    pub transmitter: tokio::sync::broadcast::Sender<SocketEvent>,
     // pub socket_streams: Vec<Arc<Mutex<SocketStreams>>>,
 }
 
-#[derive(Debug)]
+/// The state of a session at one point, never changed once shared, see `SharedSession`.
+#[derive(Debug, Clone)]
 pub struct Session {
-    refresh_jwt: Jwt,
-    access_jwt: Jwt,
-    permissions: Vec<Arc<String>>,
+    // shared by the snapshots of the session
+    refresh_jwt: Arc<Jwt>,
+    access_jwt: Arc<Jwt>,
+    // shared with the requests using them
+    permissions: Arc<PermissionSet>,
+    permission_metadata: Metadata,
+    // grants that run out, removed by `scheduler::Deadline::PermissionExpiry`
+    permission_expires_at: HashMap<String, u64>,
+    // the provider's ttl, after this the permissions are looked up again
+    permissions_expire_at: Option<u64>,
+    // when the provider last answered for this user
+    permissions_fetched_at: u64,
+    // serving the last known permissions while the provider fails
+    degraded: bool,
     socket_session: Option<Arc<SocketSession>>,
+    // keys peers minted for the session while it already had a socket session here
+    peer_socket_keys: Vec<String>,
+    // grows with every change, so replicas can tell an older copy of the session from a newer one
+    version: u64,
 }
 
-lazy_static::lazy_static! {
-    static ref GLOBAL_STRINGS: RwLock<HashMap<String, Arc<String>>> = RwLock::new(HashMap::new());
+// a version later than `version` and, as far as the clocks agree, than any change a replica
+// made before now
+fn next_version(version: u64) -> u64 {
+    version
+        .saturating_add(1)
+        .max(utils::get_current_unix_timestamp_ms())
+}
+
+fn is_active(expires_at: &HashMap<String, u64>, permission: &str, now: u64) -> bool {
+    expires_at
+        .get(permission)
+        .is_none_or(|expires_at| *expires_at > now)
 }
 
 impl Session {
     pub fn new(refresh_jwt: Jwt, access_jwt: Jwt) -> Self {
+        Session::with_tokens(Arc::new(refresh_jwt), Arc::new(access_jwt))
+    }
+
+    fn with_tokens(refresh_jwt: Arc<Jwt>, access_jwt: Arc<Jwt>) -> Self {
         Session {
             refresh_jwt,
             access_jwt,
-            permissions: vec![],
+            permissions: Arc::default(),
+            permission_metadata: Metadata::new(),
+            permission_expires_at: HashMap::new(),
+            permissions_expire_at: None,
+            permissions_fetched_at: 0,
+            degraded: false,
             socket_session: None,
+            peer_socket_keys: Vec::new(),
+            version: next_version(0),
         }
     }
 
@@ -46,8 +99,8 @@ impl Session {
         for cookie in cookies {
             if cookie.starts_with(access_jwt_cookie_name) {
                 if let Some(value) = cookie.get(access_jwt_cookie_name.len()..) {
-                    if value.starts_with('=') {
-                        access_jwt = Some(Jwt::from(&value[1..])?);
+                    if let Some(value) = value.strip_prefix('=') {
+                        access_jwt = Some(Jwt::from(value)?);
                         if access_jwt.is_some() && refresh_jwt.is_some() {
                             break;
                         }
@@ -55,8 +108,8 @@ impl Session {
                 }
             } else if cookie.starts_with(refresh_jwt_cookie_name) {
                 if let Some(value) = cookie.get(refresh_jwt_cookie_name.len()..) {
-                    if value.starts_with('=') {
-                        refresh_jwt = Some(Jwt::from(&value[1..])?);
+                    if let Some(value) = value.strip_prefix('=') {
+                        refresh_jwt = Some(Jwt::from(value)?);
                         if access_jwt.is_some() && refresh_jwt.is_some() {
                             break;
                         }
@@ -88,6 +141,11 @@ impl Session {
         Ok(Session::new(refresh_jwt, access_jwt))
     }
 
+    /// A session with the same tokens and nothing else, for lookups made away from the store.
+    pub fn detached(&self) -> Session {
+        Session::with_tokens(self.refresh_jwt.clone(), self.access_jwt.clone())
+    }
+
     pub fn set_socket_session(&mut self, uuid: String, hash: String) {
         let (tx, _) = tokio::sync::broadcast::channel(16);
         let socket_session = SocketSession {
@@ -98,34 +156,115 @@ impl Session {
         self.socket_session = Some(Arc::new(socket_session));
     }
 
-    pub fn update_socket_session(&mut self, socket_session: &Arc<SocketSession>) {
-        self.socket_session = Some(socket_session.clone());
+    // nothing is listening when the session has no open websockets
+    pub fn notify_sockets(&self, event: SocketEvent) {
+        if let Some(socket_session) = &self.socket_session {
+            let _ = socket_session.transmitter.send(event);
+        }
     }
 
-    fn get_or_insert_arc_string(value: &str) -> Arc<String> {
-        {
-            let global_strings = GLOBAL_STRINGS.read().unwrap();
-            if let Some(arc_string) = global_strings.get(value) {
-                return arc_string.clone();
-            }
+    pub fn set_permissions(&mut self, permissions: Vec<String>) {
+        self.permissions = Arc::new(PermissionSet::new(permissions.iter().map(String::as_str)));
+    }
+
+    // the set without the grants that ran out, built anew only until the scheduler removes them
+    fn active_permissions(&self, now: u64) -> PermissionSet {
+        PermissionSet::new(
+            self.permissions
+                .names()
+                .iter()
+                .map(|name| &**name)
+                .filter(|name| is_active(&self.permission_expires_at, name, now)),
+        )
+    }
+
+    // only the grants that have not run out yet
+    pub fn get_permissions(&self) -> Arc<PermissionSet> {
+        let now = utils::get_current_unix_timestamp();
+        match self.next_permission_expiry() {
+            Some(next) if next <= now => Arc::new(self.active_permissions(now)),
+            _ => self.permissions.clone(),
         }
+    }
 
-        let mut global_strings = GLOBAL_STRINGS.write().unwrap();
-        global_strings
-            .entry(value.to_string())
-            .or_insert_with(|| Arc::new(value.to_string()))
-            .clone()
+    /// Takes everything a permission provider returned.
+    pub fn apply_permissions(&mut self, permissions: Permissions) {
+        self.set_permissions(permissions.permissions);
+        self.permission_metadata = permissions.metadata;
+        self.permission_expires_at = permissions.expires_at;
+        self.permissions_expire_at = permissions
+            .ttl
+            .map(|ttl| utils::get_current_unix_timestamp() + ttl.as_secs());
+        self.permissions_fetched_at = utils::get_current_unix_timestamp();
+        self.degraded = false;
     }
 
-    pub fn set_permissions(&mut self, permissions: Vec<String>) {
-        self.permissions = permissions
-            .into_iter()
-            .map(|s| Session::get_or_insert_arc_string(s.as_str()))
-            .collect()
+    /// Takes the permissions `last` was given while the provider fails, limited to those `safe`
+    /// grants, and looks them up again at `retry_at`.
+    pub fn degrade_from(&mut self, last: &Session, safe: Option<&[Permission]>, retry_at: u64) {
+        self.permissions = match safe {
+            Some(safe) => Arc::new(last.permissions.restrict(safe)),
+            None => last.permissions.clone(),
+        };
+        self.permission_metadata = last.permission_metadata.clone();
+        self.permission_expires_at = last.permission_expires_at.clone();
+        self.permissions_expire_at = Some(retry_at);
+        self.permissions_fetched_at = last.permissions_fetched_at;
+        self.degraded = true;
+    }
+
+    /// Takes what a lookup started from the snapshot `base` found: the access token, unless a
+    /// later one arrived meanwhile, and the permissions, unless they changed since `base`. A
+    /// pushed change or a removed grant made during the lookup is newer than its answer.
+    pub fn merge_fetched(&mut self, fetched: &Session, base: &Session) {
+        if fetched.access_jwt.get_payload().exp >= self.access_jwt.get_payload().exp {
+            self.access_jwt = fetched.access_jwt.clone();
+        }
+        if !Arc::ptr_eq(&self.permissions, &base.permissions) {
+            return;
+        }
+        self.permissions = fetched.permissions.clone();
+        self.permission_metadata = fetched.permission_metadata.clone();
+        self.permission_expires_at = fetched.permission_expires_at.clone();
+        self.permissions_expire_at = fetched.permissions_expire_at;
+        self.permissions_fetched_at = fetched.permissions_fetched_at;
+        self.degraded = fetched.degraded;
+    }
+
+    pub fn get_permissions_fetched_at(&self) -> u64 {
+        self.permissions_fetched_at
     }
 
-    pub fn get_permissions(&self) -> Vec<Arc<String>> {
-        self.permissions.clone()
+    pub fn is_degraded(&self) -> bool {
+        self.degraded
+    }
+
+    pub fn next_permission_expiry(&self) -> Option<u64> {
+        self.permission_expires_at.values().min().copied()
+    }
+
+    // true when any grant was removed
+    pub fn remove_expired_permissions(&mut self, now: u64) -> bool {
+        if self.next_permission_expiry().is_none_or(|next| next > now) {
+            return false;
+        }
+        self.permissions = Arc::new(self.active_permissions(now));
+        self.permission_expires_at
+            .retain(|_, expires_at| *expires_at > now);
+        true
+    }
+
+    pub fn get_permission_metadata(&self) -> &Metadata {
+        &self.permission_metadata
+    }
+
+    pub fn permissions_expired(&self) -> bool {
+        self.permissions_expire_at
+            .is_some_and(|expire_at| expire_at <= utils::get_current_unix_timestamp())
+    }
+
+    pub fn get_permissions_expire_at(&self) -> Option<u64> {
+        self.permissions_expire_at
     }
 
     pub fn get_access_jwt(&self) -> &Jwt {
@@ -139,4 +278,260 @@ impl Session {
     pub fn get_socket_session(&self) -> Option<&Arc<SocketSession>> {
         self.socket_session.as_ref()
     }
+
+    // every key that leads to the session, its own and the ones minted by peers
+    pub fn socket_keys(&self) -> impl Iterator<Item = &str> {
+        self.socket_session
+            .iter()
+            .map(|socket_session| socket_session.uuid.as_str())
+            .chain(self.peer_socket_keys.iter().map(String::as_str))
+    }
+
+    pub fn has_open_sockets(&self) -> bool {
+        self.socket_session
+            .as_ref()
+            .is_some_and(|socket_session| socket_session.transmitter.receiver_count() > 0)
+    }
+}
+
+/// A session in the store. Requests and websockets load the current snapshot without blocking,
+/// changes are made to a copy that is swapped in.
+#[derive(Debug)]
+pub struct SharedSession {
+    snapshot: ArcSwap<Session>,
+    // unix milliseconds of the last request, kept apart so requests do not swap the snapshot
+    last_seen_ms: AtomicU64,
+}
+
+impl SharedSession {
+    pub fn new(session: Session) -> Self {
+        SharedSession {
+            snapshot: ArcSwap::from_pointee(session),
+            last_seen_ms: AtomicU64::new(utils::get_current_unix_timestamp_ms()),
+        }
+    }
+
+    pub fn load(&self) -> Arc<Session> {
+        self.snapshot.load_full()
+    }
+
+    /// Swaps in a copy of the snapshot with `change` made to it. `change` runs again on the
+    /// newer snapshot when another change was swapped in meanwhile, so it must only touch what
+    /// it changes; overwriting the snapshot it is given drops the other change.
+    pub fn update(&self, mut change: impl FnMut(&mut Session)) {
+        self.snapshot.rcu(|current| {
+            let mut next = Session::clone(current);
+            change(&mut next);
+            next.version = next_version(current.version);
+            next
+        });
+    }
+
+    pub fn touch(&self) {
+        self.last_seen_ms
+            .store(utils::get_current_unix_timestamp_ms(), Ordering::Relaxed);
+    }
+
+    pub fn get_last_seen_ms(&self) -> u64 {
+        self.last_seen_ms.load(Ordering::Relaxed)
+    }
+
+    pub fn to_stored(&self) -> StoredSession {
+        let session = self.load();
+        StoredSession {
+            refresh_token: session.refresh_jwt.get_full_token().to_string(),
+            access_token: session.access_jwt.get_full_token().to_string(),
+            permissions: session
+                .permissions
+                .names()
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            permission_metadata: session.permission_metadata.clone(),
+            permission_expires_at: session.permission_expires_at.clone(),
+            permissions_expire_at: session.permissions_expire_at,
+            permissions_fetched_at: session.permissions_fetched_at,
+            degraded: session.degraded,
+            socket_key: session
+                .socket_session
+                .as_ref()
+                .map(|socket_session| (socket_session.uuid.clone(), socket_session.hash.clone())),
+            last_seen_ms: self.get_last_seen_ms(),
+            version: session.version,
+        }
+    }
+
+    /// The session as it was saved, with a new channel for its websockets.
+    pub fn from_stored(stored: StoredSession) -> Result<SharedSession> {
+        let last_seen_ms = stored.last_seen_ms;
+        let shared = SharedSession::new(Session::from_stored(stored)?);
+        shared.last_seen_ms.store(last_seen_ms, Ordering::Relaxed);
+        Ok(shared)
+    }
+
+    /// Takes a peer's copy of the session, true when its permissions changed. A copy no newer
+    /// than the one here arrived late and is dropped. Open websockets keep listening on the
+    /// socket session they have, a key the peer minted meanwhile is kept next to it.
+    pub fn merge_stored(&self, stored: StoredSession) -> Result<bool> {
+        let last_seen_ms = stored.last_seen_ms;
+        let incoming = Session::from_stored(stored)?;
+        let mut changed = false;
+        self.snapshot.rcu(|current| {
+            changed = false;
+            if incoming.version <= current.version {
+                return current.clone();
+            }
+            let mut next = incoming.clone();
+            next.peer_socket_keys = current.peer_socket_keys.clone();
+            if let Some(socket_session) = &current.socket_session {
+                if let Some(other) = &incoming.socket_session {
+                    if other.uuid != socket_session.uuid
+                        && !next.peer_socket_keys.contains(&other.uuid)
+                    {
+                        next.peer_socket_keys.push(other.uuid.clone());
+                    }
+                }
+                next.socket_session = Some(socket_session.clone());
+            }
+            changed = next.permissions.forwarded() != current.permissions.forwarded();
+            Arc::new(next)
+        });
+        self.last_seen_ms.fetch_max(last_seen_ms, Ordering::Relaxed);
+        Ok(changed)
+    }
+}
+
+impl Session {
+    fn from_stored(stored: StoredSession) -> Result<Session> {
+        let mut session = Session::new(
+            Jwt::from(&stored.refresh_token)?,
+            Jwt::from(&stored.access_token)?,
+        );
+        session.set_permissions(stored.permissions);
+        session.permission_metadata = stored.permission_metadata;
+        session.permission_expires_at = stored.permission_expires_at;
+        session.permissions_expire_at = stored.permissions_expire_at;
+        session.permissions_fetched_at = stored.permissions_fetched_at;
+        session.degraded = stored.degraded;
+        session.version = stored.version;
+        if let Some((uuid, hash)) = stored.socket_key {
+            session.set_socket_session(uuid, hash);
+        }
+        Ok(session)
+    }
+}
+
+/// What is kept of a session across restarts, see `session_store`, and sent to peers.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredSession {
+    refresh_token: String,
+    access_token: String,
+    permissions: Vec<String>,
+    permission_metadata: Metadata,
+    permission_expires_at: HashMap<String, u64>,
+    permissions_expire_at: Option<u64>,
+    permissions_fetched_at: u64,
+    degraded: bool,
+    // the uuid and hash of the socket key
+    socket_key: Option<(String, String)>,
+    last_seen_ms: u64,
+    // see `Session::version`, snapshots saved before it was kept have none
+    #[serde(default)]
+    version: u64,
+}
+
+impl StoredSession {
+    pub fn refresh_token(&self) -> &str {
+        &self.refresh_token
+    }
+
+    pub fn socket_key(&self) -> Option<&str> {
+        self.socket_key.as_ref().map(|(uuid, _)| uuid.as_str())
+    }
+}
+
+#[cfg(test)]
+impl Session {
+    pub fn for_sub(sub: &str) -> Session {
+        Session::for_token(sub, "example.com", "jti")
+    }
+
+    pub fn for_token(sub: &str, iss: &str, jti: &str) -> Session {
+        use base64::prelude::*;
+
+        let payload = serde_json::json!({
+            "iss": iss,
+            "sub": sub,
+            "aud": "access",
+            "exp": utils::get_current_unix_timestamp() + 3600,
+            "nbf": 0,
+            "iat": 0,
+            "jti": jti,
+        });
+        let token = format!(
+            "e30.{}.sig",
+            BASE64_URL_SAFE_NO_PAD.encode(payload.to_string())
+        );
+        Session::new(Jwt::from(&token).unwrap(), Jwt::from(&token).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expired_permissions() {
+        let now = utils::get_current_unix_timestamp();
+        let mut session = Session::for_sub("201944");
+        session.apply_permissions(Permissions {
+            permissions: vec![
+                String::from("cta"),
+                String::from("trial"),
+                String::from("day_pass"),
+            ],
+            expires_at: HashMap::from([
+                (String::from("trial"), now - 1),
+                (String::from("day_pass"), now + 3600),
+            ]),
+            ..Default::default()
+        });
+
+        // lapsed grants are never handed out, even before they are removed
+        let permissions = session.get_permissions();
+        assert_eq!(permissions.forwarded(), "cta,day_pass");
+        assert_eq!(session.next_permission_expiry(), Some(now - 1));
+
+        assert!(session.remove_expired_permissions(now));
+        assert!(!session.remove_expired_permissions(now));
+        assert_eq!(session.next_permission_expiry(), Some(now + 3600));
+    }
+
+    #[test]
+    fn test_shared_session_update() {
+        let shared = SharedSession::new(Session::for_sub("201944"));
+        let before = shared.load();
+        shared.update(|session| session.set_permissions(vec![String::from("cta")]));
+
+        // snapshots already loaded are left as they were
+        assert!(before.get_permissions().is_empty());
+        assert_eq!(shared.load().get_permissions().forwarded(), "cta");
+    }
+
+    #[test]
+    fn test_merge_fetched_keeps_newer_changes() {
+        let shared = SharedSession::new(Session::for_sub("201944"));
+        let base = shared.load();
+        let mut fetched = base.detached();
+        fetched.set_permissions(vec![String::from("cta")]);
+
+        shared.update(|session| session.merge_fetched(&fetched, &base));
+        assert_eq!(shared.load().get_permissions().forwarded(), "cta");
+
+        // pushed while the next lookup was waiting on the provider
+        let base = shared.load();
+        shared.update(|session| session.set_permissions(vec![String::from("nasdaq")]));
+        shared.update(|session| session.merge_fetched(&fetched, &base));
+        assert_eq!(shared.load().get_permissions().forwarded(), "nasdaq");
+    }
 }
@@ -0,0 +1,228 @@
+use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit, Nonce};
+use anyhow::{anyhow, Result};
+use futures::future::BoxFuture;
+use rand::RngCore;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{io::AsyncWriteExt, sync::Mutex, task::JoinHandle};
+
+use crate::{
+    config, scheduler,
+    session::{SharedSession, StoredSession},
+    state::State,
+};
+
+// AES-GCM nonces are 96 bits, a new one for every save
+const NONCE_LEN: usize = 12;
+
+/// Keeps sessions across restarts.
+pub trait SessionStore: Send + Sync {
+    fn save(&self, sessions: Vec<StoredSession>) -> BoxFuture<'_, Result<()>>;
+
+    // nothing when there is nothing saved yet
+    fn load(&self) -> BoxFuture<'_, Result<Vec<StoredSession>>>;
+}
+
+/// Sessions as JSON in a file encrypted with AES-256-GCM, the nonce ahead of the ciphertext.
+/// Saves write a new file next to it, flush it to disk and rename it over, so a crash or power
+/// loss leaves the last one whole.
+pub struct FileSessionStore {
+    path: PathBuf,
+    cipher: Aes256Gcm,
+    // one save at a time, they share the file they write before the rename
+    saving: Mutex<()>,
+}
+
+impl FileSessionStore {
+    pub fn new(path: impl Into<PathBuf>, key: &[u8]) -> Result<Self> {
+        Ok(FileSessionStore {
+            path: path.into(),
+            cipher: Aes256Gcm::new_from_slice(key)
+                .map_err(|_| anyhow!("the session snapshot key must be 32 bytes"))?,
+            saving: Mutex::new(()),
+        })
+    }
+
+    fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let mut nonce = [0; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .map_err(|_| anyhow!("could not encrypt the session snapshot"))?;
+        Ok([&nonce[..], &ciphertext].concat())
+    }
+
+    fn open(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return Err(anyhow!("the session snapshot is truncated"));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("could not decrypt the session snapshot, was the key changed?"))
+    }
+}
+
+impl SessionStore for FileSessionStore {
+    fn save(&self, sessions: Vec<StoredSession>) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            let sealed = self.seal(&serde_json::to_vec(&sessions)?)?;
+            let _saving = self.saving.lock().await;
+            let mut partial = self.path.clone().into_os_string();
+            partial.push(".partial");
+            let mut file = tokio::fs::File::create(&partial).await?;
+            file.write_all(&sealed).await?;
+            file.sync_all().await?;
+            drop(file);
+            tokio::fs::rename(&partial, &self.path).await?;
+            // the rename is only durable once the directory holding it is
+            let dir = match self.path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            tokio::fs::File::open(dir).await?.sync_all().await?;
+            Ok(())
+        })
+    }
+
+    fn load(&self) -> BoxFuture<'_, Result<Vec<StoredSession>>> {
+        Box::pin(async move {
+            let sealed = match tokio::fs::read(&self.path).await {
+                Ok(sealed) => sealed,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(err) => return Err(err.into()),
+            };
+            Ok(serde_json::from_slice(&self.open(&sealed)?)?)
+        })
+    }
+}
+
+pub fn build(config: &config::Config) -> Result<Option<Box<dyn SessionStore>>> {
+    let Some(snapshot) = &config.session_snapshot else {
+        return Ok(None);
+    };
+    let key = std::env::var(&snapshot.key_env)
+        .map_err(|_| anyhow!("${} is not set", snapshot.key_env))?;
+    let key = hex::decode(key.trim()).map_err(|_| anyhow!("${} is not hex", snapshot.key_env))?;
+    Ok(Some(Box::new(FileSessionStore::new(&snapshot.path, &key)?)))
+}
+
+/// Writes every session that has not expired, returns how many.
+pub async fn save(state: &State) -> Result<usize> {
+    let Some(store) = &state.session_store else {
+        return Ok(0);
+    };
+    let sessions = state
+        .sessions
+        .all()
+        .iter()
+        .filter(|session| !session.load().get_refresh_jwt().is_expired())
+        .map(|session| session.to_stored())
+        .collect::<Vec<StoredSession>>();
+    let saved = sessions.len();
+    store.save(sessions).await?;
+    Ok(saved)
+}
+
+/// Takes back the sessions saved before the restart, returns how many.
+pub async fn restore(state: &State) -> Result<usize> {
+    let Some(store) = &state.session_store else {
+        return Ok(0);
+    };
+    let mut restored = 0;
+    for stored in store.load().await? {
+        let session = match SharedSession::from_stored(stored) {
+            Ok(session) => session,
+            Err(err) => {
+                eprintln!("Error restoring a session: {:?}", err);
+                continue;
+            }
+        };
+        // ended while the gateway was down
+        if session.load().get_refresh_jwt().is_expired() {
+            continue;
+        }
+        let session = state.sessions.restore(session);
+        scheduler::watch(state, &session);
+        restored += 1;
+    }
+    Ok(restored)
+}
+
+/// Saves every `session_snapshot.save_interval_ms`, until the returned task is aborted.
+pub fn start(state: Arc<State>) -> Option<JoinHandle<()>> {
+    let snapshot = state.config.session_snapshot.as_ref()?;
+    let period = Duration::from_millis(snapshot.save_interval_ms.max(1));
+    Some(tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        // the first tick is right away, nothing changed since the restore
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(err) = save(&state).await {
+                eprintln!("Error saving sessions: {:?}", err);
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{permission::Permissions, session::Session};
+
+    #[tokio::test]
+    async fn test_saves_do_not_interleave() {
+        let path = std::env::temp_dir().join(format!("sessions-{}", crate::utils::generate_uuid()));
+        let store = FileSessionStore::new(&path, &[7; 32]).unwrap();
+        let sessions = |count: usize| {
+            (0..count)
+                .map(|n| SharedSession::new(Session::for_sub(&n.to_string())).to_stored())
+                .collect::<Vec<StoredSession>>()
+        };
+
+        let (first, second) = tokio::join!(store.save(sessions(200)), store.save(sessions(3)));
+        first.unwrap();
+        second.unwrap();
+        let loaded = store.load().await.unwrap().len();
+        assert!(loaded == 200 || loaded == 3);
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_file_session_store() {
+        let path = std::env::temp_dir().join(format!("sessions-{}", crate::utils::generate_uuid()));
+        let store = FileSessionStore::new(&path, &[7; 32]).unwrap();
+        assert!(store.load().await.unwrap().is_empty());
+
+        let mut session = Session::for_sub("201944");
+        session.apply_permissions(Permissions {
+            permissions: vec![String::from("cta")],
+            ..Default::default()
+        });
+        session.set_socket_session(String::from("uuid"), String::from("hash"));
+        store
+            .save(vec![SharedSession::new(session).to_stored()])
+            .await
+            .unwrap();
+
+        let restored = store.load().await.unwrap();
+        assert_eq!(restored.len(), 1);
+        let restored = SharedSession::from_stored(restored.into_iter().next().unwrap()).unwrap();
+        let restored = restored.load();
+        assert_eq!(restored.get_permissions().forwarded(), "cta");
+        assert_eq!(restored.get_socket_session().unwrap().uuid, "uuid");
+
+        // encrypted at rest
+        let sealed = std::fs::read(&path).unwrap();
+        assert!(!String::from_utf8_lossy(&sealed).contains("cta"));
+        let other = FileSessionStore::new(&path, &[8; 32]).unwrap();
+        assert!(other.load().await.is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
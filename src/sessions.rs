@@ -1,84 +1,407 @@
-use anyhow::{anyhow, Result};
-use std::collections::HashMap;
+use dashmap::{mapref::entry::Entry, DashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::sync::RwLock;
 
-use crate::session::Session;
+use crate::{
+    config,
+    jwt::Jwt,
+    session::{Session, SharedSession},
+    utils,
+};
 
-type Sessions = HashMap<String, Arc<RwLock<Session>>>;
+// split into shards with a lock each, the locks do not poison
+type Sessions = DashMap<String, Arc<SharedSession>>;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Eviction {
+    // the refresh token expired
+    Expired,
+    Idle,
+    // the least recently used past `max_sessions`
+    Capacity,
+    // the user logged out
+    Logout,
+}
+
+impl Eviction {
+    // sent to the session's open websockets
+    pub fn message(&self) -> &'static str {
+        match self {
+            Eviction::Expired => "Session expired",
+            Eviction::Idle => "Session idle",
+            Eviction::Capacity => "Session evicted",
+            Eviction::Logout => "Logged out",
+        }
+    }
+}
+
+/// Sessions dropped so far, by why.
+#[derive(Debug, Default)]
+pub struct Evictions {
+    pub expired: AtomicU64,
+    pub idle: AtomicU64,
+    pub capacity: AtomicU64,
+    pub logout: AtomicU64,
+}
+
+impl Evictions {
+    fn count(&self, eviction: Eviction) {
+        let counter = match eviction {
+            Eviction::Expired => &self.expired,
+            Eviction::Idle => &self.idle,
+            Eviction::Capacity => &self.capacity,
+            Eviction::Logout => &self.logout,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// a guard into one map is never held while taking one into the other, sessions take no lock
 #[derive(Debug)]
 pub struct SafeSessions {
-    refresh_token_to_session: Arc<RwLock<Sessions>>,
-    socket_key_to_session: Arc<RwLock<Sessions>>,
+    config: config::Sessions,
+    refresh_token_to_session: Sessions,
+    socket_key_to_session: Sessions,
+    // refresh token of a session that ended -> when the token expires, so neither a replayed
+    // cookie nor a copy a peer sends late brings the session back
+    ended: DashMap<String, u64>,
+    pub evictions: Evictions,
 }
 
 impl SafeSessions {
-    pub fn new() -> Self {
+    pub fn new(config: config::Sessions) -> Self {
         SafeSessions {
-            refresh_token_to_session: Arc::new(RwLock::new(HashMap::new())),
-            socket_key_to_session: Arc::new(RwLock::new(HashMap::new())),
+            config,
+            refresh_token_to_session: DashMap::new(),
+            socket_key_to_session: DashMap::new(),
+            ended: DashMap::new(),
+            evictions: Evictions::default(),
         }
     }
 
-    pub fn insert<'a>(&self, session: Session) -> Result<Arc<std::sync::RwLock<Session>>> {
-        let mut map: std::sync::RwLockWriteGuard<'_, HashMap<String, Arc<RwLock<Session>>>> = self
-            .refresh_token_to_session
-            .write()
-            .map_err(|_e| anyhow!("could not lock key set"))?;
+    pub fn len(&self) -> usize {
+        self.refresh_token_to_session.len()
+    }
 
-        let token = session.get_refresh_jwt().get_full_token().to_string();
-        let session = Arc::new(RwLock::new(session));
-        map.insert(token, session.clone());
-        Ok(session)
+    // removes the sessions `pick` gives a reason for, along with their socket keys
+    fn evict(
+        &self,
+        mut pick: impl FnMut(&SharedSession) -> Option<Eviction>,
+    ) -> Vec<(Eviction, Arc<SharedSession>)> {
+        let mut evicted = Vec::new();
+        self.refresh_token_to_session.retain(|_, session| {
+            let Some(eviction) = pick(session) else {
+                return true;
+            };
+            self.evictions.count(eviction);
+            evicted.push((eviction, session.clone()));
+            false
+        });
+
+        for (_, session) in &evicted {
+            self.remove_socket_key(&session.load());
+        }
+        evicted
+    }
+
+    fn remove_socket_key(&self, session: &Session) {
+        for key in session.socket_keys() {
+            self.socket_key_to_session.remove(key);
+        }
     }
 
-    pub fn remove(&self, session: &Session) -> Result<Option<Arc<RwLock<Session>>>> {
-        let mut map = self
+    /// Drops the session of `token`, when it ended somewhere else.
+    fn remove(&self, token: &str) -> Option<Arc<SharedSession>> {
+        let (_, session) = self.refresh_token_to_session.remove(token)?;
+        self.remove_socket_key(&session.load());
+        Some(session)
+    }
+
+    /// Drops the session of `token` when the user logs out, the token does not start a session
+    /// again.
+    pub fn logout(&self, token: &str) -> Vec<(Eviction, Arc<SharedSession>)> {
+        self.end(token);
+        let Some(session) = self.remove(token) else {
+            return Vec::new();
+        };
+        self.evictions.count(Eviction::Logout);
+        vec![(Eviction::Logout, session)]
+    }
+
+    /// Drops the session of `token` when a peer says it ended.
+    pub fn end_from_peer(&self, token: &str) -> Option<Arc<SharedSession>> {
+        self.end(token);
+        self.remove(token)
+    }
+
+    // kept until the token expires, it cannot start a session after that anyway
+    fn end(&self, token: &str) {
+        let Ok(jwt) = Jwt::from(token) else {
+            return;
+        };
+        if !jwt.is_expired() {
+            self.ended.insert(token.to_string(), jwt.get_payload().exp);
+        }
+    }
+
+    /// Whether the session of `token` was ended, by a logout here or on a peer.
+    pub fn is_ended(&self, token: &str) -> bool {
+        self.ended.contains_key(token)
+    }
+
+    /// Drops the sessions that went idle without open websockets.
+    pub fn sweep(&self) -> Vec<(Eviction, Arc<SharedSession>)> {
+        let now = utils::get_current_unix_timestamp();
+        self.ended.retain(|_, expires_at| *expires_at >= now);
+        let idle_since =
+            utils::get_current_unix_timestamp_ms().saturating_sub(self.config.idle_timeout_ms);
+        self.evict(|session| {
+            (session.get_last_seen_ms() < idle_since && !session.load().has_open_sockets())
+                .then_some(Eviction::Idle)
+        })
+    }
+
+    /// Drops the session of `token` once its refresh token expired, when the scheduler says so.
+    pub fn remove_expired(&self, token: &str) -> Vec<(Eviction, Arc<SharedSession>)> {
+        let Some((_, session)) = self
             .refresh_token_to_session
-            .write()
-            .map_err(|_e| anyhow!("could not lock key set"))?;
-        Ok(map.remove(session.get_refresh_jwt().get_full_token()))
+            .remove_if(token, |_, session| {
+                session.load().get_refresh_jwt().is_expired()
+            })
+        else {
+            return Vec::new();
+        };
+
+        self.remove_socket_key(&session.load());
+        self.evictions.count(Eviction::Expired);
+        vec![(Eviction::Expired, session)]
     }
 
-    pub fn update(&self, mut session: Session) -> Result<Arc<RwLock<Session>>> {
-        let mut map = self
+    /// Drops the least recently used sessions once there are more than `max_sessions`.
+    pub fn evict_least_recent(&self) -> Vec<(Eviction, Arc<SharedSession>)> {
+        let max = self.config.max_sessions;
+        let len = self.refresh_token_to_session.len();
+        if len <= max {
+            return Vec::new();
+        }
+        // a batch at a time, so a full store is not searched for every new session
+        let mut excess = len - max + max / 100;
+        let mut last_seen = self
             .refresh_token_to_session
-            .write()
-            .map_err(|_e| anyhow!("could not lock key set"))?;
+            .iter()
+            .map(|entry| entry.value().get_last_seen_ms())
+            .collect::<Vec<u64>>();
+        // sessions may have gone meanwhile
+        excess = excess.min(last_seen.len());
+        if excess == 0 {
+            return Vec::new();
+        }
+        let (_, cutoff, _) = last_seen.select_nth_unstable(excess - 1);
+        let cutoff = *cutoff;
+
+        self.evict(|session| {
+            if excess == 0 || session.get_last_seen_ms() > cutoff {
+                return None;
+            }
+            excess -= 1;
+            Some(Eviction::Capacity)
+        })
+    }
+
+    pub fn insert(&self, session: Session) -> Arc<SharedSession> {
         let token = session.get_refresh_jwt().get_full_token().to_string();
+        let session = Arc::new(SharedSession::new(session));
+        self.refresh_token_to_session.insert(token, session.clone());
+        session
+    }
 
-        if let Some(old_session) = map.get(&token) {
-            if let Some(old_session) = old_session.read().ok() {
-                session.update_socket_session(
-                    &old_session
-                        .get_socket_session()
-                        .ok_or_else(|| anyhow!("could not read old_session"))?
-                        .clone(),
-                );
+    // a lookup started from `base` is merged into the current snapshot, see
+    // `Session::merge_fetched`, open websockets and socket keys keep pointing at the session
+    pub fn update(&self, session: Session, base: &Session) -> Arc<SharedSession> {
+        let token = session.get_refresh_jwt().get_full_token().to_string();
+        match self.refresh_token_to_session.entry(token) {
+            Entry::Occupied(entry) => {
+                let shared = entry.get().clone();
+                drop(entry);
+                shared.update(|current| current.merge_fetched(&session, base));
+                shared.touch();
+                shared
             }
+            Entry::Vacant(entry) => entry.insert(Arc::new(SharedSession::new(session))).clone(),
         }
+    }
 
-        let session = Arc::new(RwLock::new(session));
-        map.insert(token, session.clone());
-        Ok(session)
+    // every session of a user, one per login
+    pub fn get_by_sub(&self, sub: &str) -> Vec<Arc<SharedSession>> {
+        self.refresh_token_to_session
+            .iter()
+            .filter(|entry| entry.value().load().get_access_jwt().get_payload().sub == sub)
+            .map(|entry| entry.value().clone())
+            .collect()
     }
 
-    pub fn get(&self, session: &Session) -> Result<Option<Arc<RwLock<Session>>>> {
-        let map = self
-            .refresh_token_to_session
-            .read()
-            .map_err(|_e| anyhow!("could not lock key set"))?;
+    /// Takes back a session saved before a restart, along with its socket key.
+    pub fn restore(&self, session: SharedSession) -> Arc<SharedSession> {
+        let session = Arc::new(session);
+        let snapshot = session.load();
+        self.refresh_token_to_session.insert(
+            snapshot.get_refresh_jwt().get_full_token().to_string(),
+            session.clone(),
+        );
+        if let Some(socket_session) = snapshot.get_socket_session() {
+            self.insert_socket_key(&socket_session.uuid, session.clone());
+        }
+        session
+    }
 
-        let token = session.get_refresh_jwt().get_full_token().to_string();
-        Ok(map.get(&token).cloned())
+    pub fn all(&self) -> Vec<Arc<SharedSession>> {
+        self.refresh_token_to_session
+            .iter()
+            .map(|entry| entry.value().clone())
+            .collect()
     }
 
-    pub fn get_from_websocket_key(&self, key: &str) -> Result<Option<Arc<RwLock<Session>>>> {
-        let map = self
-            .socket_key_to_session
-            .read()
-            .map_err(|_e| anyhow!("could not lock key set"))?;
-        Ok(map.get(key).cloned())
+    pub fn insert_socket_key(&self, key: &str, session: Arc<SharedSession>) {
+        self.socket_key_to_session.insert(key.to_string(), session);
+    }
+
+    pub fn get(&self, session: &Session) -> Option<Arc<SharedSession>> {
+        self.get_by_token(session.get_refresh_jwt().get_full_token())
+    }
+
+    pub fn get_by_token(&self, token: &str) -> Option<Arc<SharedSession>> {
+        self.refresh_token_to_session
+            .get(token)
+            .map(|session| session.clone())
+    }
+
+    pub fn get_from_websocket_key(&self, key: &str) -> Option<Arc<SharedSession>> {
+        self.socket_key_to_session
+            .get(key)
+            .map(|session| session.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::permission::Permissions;
+    use std::collections::HashMap;
+    use std::sync::RwLock;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_evict_least_recent() {
+        let sessions = SafeSessions::new(config::Sessions {
+            max_sessions: 2,
+            ..Default::default()
+        });
+        for sub in ["a", "b", "c"] {
+            sessions.insert(Session::for_sub(sub));
+        }
+
+        assert!(sessions.sweep().is_empty());
+        let evicted = sessions.evict_least_recent();
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].0, Eviction::Capacity);
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions.evictions.capacity.load(Ordering::Relaxed), 1);
+        assert!(sessions.evict_least_recent().is_empty());
+    }
+
+    #[test]
+    fn test_ended_sessions_stay_ended() {
+        let sessions = SafeSessions::new(config::Sessions::default());
+        let session = sessions.insert(Session::for_sub("a"));
+        let token = session
+            .load()
+            .get_refresh_jwt()
+            .get_full_token()
+            .to_string();
+
+        assert_eq!(sessions.logout(&token).len(), 1);
+        assert!(sessions.is_ended(&token));
+        // the token is refused for the rest of its life, the sweep keeps it
+        sessions.sweep();
+        assert!(sessions.is_ended(&token));
+        assert!(!sessions.is_ended("other"));
+    }
+
+    const BENCH_SESSIONS: usize = 10_000;
+    const BENCH_THREADS: usize = 8;
+    const BENCH_OPS: usize = 200_000;
+    // one operation in this many refreshes a session's permissions, the rest read them
+    const BENCH_WRITE_EVERY: usize = 20;
+
+    // the store before it was sharded, a lock around the map and one around each session
+    #[derive(Default)]
+    struct LockedSessions {
+        map: RwLock<HashMap<String, Arc<RwLock<Session>>>>,
+    }
+
+    fn time(op: impl Fn(usize) + Sync) -> Duration {
+        let start = Instant::now();
+        std::thread::scope(|scope| {
+            for thread in 0..BENCH_THREADS {
+                let op = &op;
+                scope.spawn(move || {
+                    for i in 0..BENCH_OPS {
+                        op(thread * BENCH_OPS + i);
+                    }
+                });
+            }
+        });
+        start.elapsed()
+    }
+
+    // cargo test --release bench_session_store -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_session_store() {
+        let locked = LockedSessions::default();
+        let sharded = SafeSessions::new(config::Sessions::default());
+        let mut tokens = Vec::new();
+        for n in 0..BENCH_SESSIONS {
+            let session = Session::for_sub(&n.to_string());
+            let token = session.get_refresh_jwt().get_full_token().to_string();
+            locked
+                .map
+                .write()
+                .unwrap()
+                .insert(token.clone(), Arc::new(RwLock::new(session.clone())));
+            sharded.insert(session);
+            tokens.push(token);
+        }
+        let permissions = Permissions {
+            permissions: vec![String::from("cta"), String::from("nasdaq")],
+            ..Default::default()
+        };
+        // spread over the sessions so threads do not walk them in step
+        let pick = |n: usize| &tokens[n.wrapping_mul(7919) % BENCH_SESSIONS];
+
+        let locked_time = time(|n| {
+            let session = locked.map.read().unwrap().get(pick(n)).cloned().unwrap();
+            if n % BENCH_WRITE_EVERY == 0 {
+                session
+                    .write()
+                    .unwrap()
+                    .apply_permissions(permissions.clone());
+            } else {
+                std::hint::black_box(session.read().unwrap().get_permissions());
+            }
+        });
+        let sharded_time = time(|n| {
+            let session = sharded.get_by_token(pick(n)).unwrap();
+            if n % BENCH_WRITE_EVERY == 0 {
+                session.update(|session| session.apply_permissions(permissions.clone()));
+            } else {
+                session.touch();
+                std::hint::black_box(session.load().get_permissions());
+            }
+        });
+
+        let ops = (BENCH_THREADS * BENCH_OPS) as f64;
+        println!("locked:  {:>12.0} ops/s", ops / locked_time.as_secs_f64());
+        println!("sharded: {:>12.0} ops/s", ops / sharded_time.as_secs_f64());
     }
 }
@@ -4,44 +4,42 @@ use hyper::{body::Bytes, Response};
 use std::sync::Arc;
 
 use crate::{
-    config,
-    session::{Session, SocketSession},
+    peers,
+    session::{SharedSession, SocketSession},
+    state::State,
     utils,
 };
 
 pub fn gen_socket_key(
-    session: &Arc<std::sync::RwLock<Session>>,
-    config: &Arc<config::Config>,
+    session: &Arc<SharedSession>,
+    state: &State,
 ) -> Result<Response<Full<Bytes>>> {
-    if session
-        .read()
-        .or(Err(anyhow!("could not read from RWLock")))?
-        .get_permissions()
-        .len()
-        == 0
-    {
+    if session.load().get_permissions().is_empty() {
         return Err(anyhow!(
             "You do not seem to have the permission to access this service",
         ));
     }
 
+    // open websockets keep listening on the existing socket session
+    if session.load().get_socket_session().is_none() {
+        let uuid = utils::generate_uuid();
+        let hash = utils::cypher_hash_string(&uuid, &state.config.socket_encryption_key);
+        session.update(|session| {
+            // another request may have swapped one in first
+            if session.get_socket_session().is_none() {
+                session.set_socket_session(uuid.clone(), hash.clone());
+            }
+        });
+        peers::publish(state, session);
+    }
     let (uuid, hash) = session
-        .read()
-        .or(Err(anyhow!("could not read from RWLock")))?
+        .load()
         .get_socket_session()
-        .map_or_else(
-            || {
-                let uuid = utils::generate_uuid();
-                let hash = utils::cypher_hash_string(&uuid, &config.socket_encryption_key);
-                (uuid, hash)
-            },
-            |user: &Arc<SocketSession>| (user.uuid.clone(), user.hash.clone()),
-        );
-
-    session
-        .write()
-        .or(Err(anyhow!("could not write from RWLock")))?
-        .set_socket_session(uuid.clone(), hash.to_string());
+        .map(|socket_session: &Arc<SocketSession>| {
+            (socket_session.uuid.clone(), socket_session.hash.clone())
+        })
+        .ok_or_else(|| anyhow!("could not get socket session"))?;
+    state.sessions.insert_socket_key(&uuid, session.clone());
 
     Ok(Response::new(Full::new(Bytes::from(
         (uuid.clone() + "." + hash.as_str()).to_string(),
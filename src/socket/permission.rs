@@ -24,9 +24,8 @@ pub fn extract_socket_key_from_utl(url: &Uri, encryption_key: &str) -> Result<St
     Some(key)
         .filter(|s| !s.is_empty())
         .map_or(Err(anyhow!("invalid Socket key")), |s| {
-            let uuid = s.split('.').nth(0).ok_or(anyhow!("invalid Socket key"))?;
-            let hash = utils::cypher_hash_string(uuid, encryption_key);
-            if hash == s {
+            let (uuid, hash) = s.split_once('.').ok_or(anyhow!("invalid Socket key"))?;
+            if utils::cypher_hash_string(uuid, encryption_key) == hash {
                 Ok(uuid.to_string())
             } else {
                 Err(anyhow!("invalid Socket key"))
@@ -1,17 +1,23 @@
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 
 use anyhow::{anyhow, Result};
 use futures::sink::SinkExt;
 use futures::stream::StreamExt;
 use http_body_util::Full;
 use hyper::body::Bytes;
-use hyper::{Request, Response};
+use hyper::{Request, Response, Uri};
 use hyper_tungstenite::HyperWebsocket;
-use tokio::task::JoinHandle;
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
 use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 
-use crate::session::Session;
-use crate::{config, sessions};
+use crate::permission::PermissionSet;
+use crate::session::{SharedSession, SocketEvent};
+use crate::state::State;
+use crate::{config, forwarding, routes, sessions};
 
 async fn close_socket(websocket: HyperWebsocket, err: Option<anyhow::Error>) -> Result<()> {
     let mut ws = websocket.await?;
@@ -22,99 +28,176 @@ async fn close_socket(websocket: HyperWebsocket, err: Option<anyhow::Error>) ->
     Ok(())
 }
 
-async fn serve_websocket(
-    websocket: HyperWebsocket,
-    config: &Arc<config::Config>,
-    session: &Arc<RwLock<Session>>,
-) -> Result<()> {
-    let client_ws_stream = websocket.await?;
+// websockets use the same endpoints as http, `http://` becomes `ws://` and `https://` becomes `wss://`
+fn websocket_uri(endpoint: &Uri, path_and_query: &str) -> Result<Uri> {
+    let scheme = match endpoint.scheme_str() {
+        Some("https") | Some("wss") => "wss",
+        _ => "ws",
+    };
+    Ok(Uri::builder()
+        .scheme(scheme)
+        .authority(
+            endpoint
+                .authority()
+                .ok_or_else(|| anyhow!("endpoint has no authority"))?
+                .clone(),
+        )
+        .path_and_query(path_and_query)
+        .build()?)
+}
 
-    // Connect to the target server
-    let (server_ws_stream, _) = tokio_tungstenite::connect_async(&config.sidecar_url).await?;
+// the session's permissions, or why the socket may not stay open
+fn current_permissions(
+    session: &SharedSession,
+    route: Option<&config::Route>,
+) -> std::result::Result<Arc<PermissionSet>, &'static str> {
+    let session = session.load();
+    if session.get_access_jwt().is_expired() {
+        return Err("Session expired");
+    }
+    let permissions = session.get_permissions();
+    if permissions.is_empty() || route.is_some_and(|route| !route.allows(&permissions)) {
+        return Err("Permission revoked");
+    }
+    Ok(permissions)
+}
 
-    let (mut server_write, mut server_read) = server_ws_stream.split();
-    let (mut client_write, mut client_read) = client_ws_stream.split();
+async fn connect(
+    state: &State,
+    route: Option<&config::Route>,
+    endpoint: &Uri,
+    uri: &Uri,
+    permissions: &PermissionSet,
+) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>> {
+    let uri = websocket_uri(
+        endpoint,
+        &forwarding::upstream_path_and_query(uri, permissions.forwarded()),
+    )?;
+
+    let probe = state
+        .upstream
+        .breaker(route)
+        .map(|breaker| breaker.acquire())
+        .transpose()?;
+    let connection = tokio_tungstenite::connect_async(uri).await;
+    if let Some(probe) = probe {
+        probe.release(connection.is_ok());
+    }
+    Ok(connection?.0)
+}
 
-    let session_inst = session.clone();
+fn close_frame(reason: &str) -> Option<CloseFrame<'static>> {
+    Some(CloseFrame {
+        code: CloseCode::Policy,
+        reason: reason.to_string().into(),
+    })
+}
 
-    // Forward messages from the client to the server
-    let server_to_client: JoinHandle<Result<()>> = tokio::spawn(async move {
-        let mut receiver = session_inst
-            .read()
-            .or(Err(anyhow!("could not read from RWLock")))?
+async fn serve_websocket(
+    websocket: HyperWebsocket,
+    state: &Arc<State>,
+    route: Option<&config::Route>,
+    uri: &Uri,
+    session: &SharedSession,
+) -> Result<()> {
+    let upstream = &state.upstream;
+    let (sub, mut events) = {
+        let session = session.load();
+        let events = session
             .get_socket_session()
             .ok_or_else(|| anyhow!("could not get socket session"))?
             .transmitter
             .subscribe();
+        (session.get_access_jwt().get_payload().sub.clone(), events)
+    };
 
-        loop {
-            tokio::select! {
-                msg = server_read.next() => {
-                if let Some(msg) = msg {
-                    let msg = msg?;
-                    if session_inst
-                    .read()
-                    .or(Err(anyhow!("could not read from RWLock")))?
-                    .get_access_jwt()
-                    .is_expired()
-                    {
-                        client_write.close().await?;
-                        break;
-                    }
-                    client_write.send(msg).await?;
-                }
-                },
-                _ = receiver.recv() => {
-                // Handle receiver event if needed
-                    client_write.close().await?;
-                    break;
-                }
-            }
-        }
-        Ok(())
-    });
+    // held until the socket closes so it counts as a connection on the endpoint
+    let guard = upstream
+        .pick(route, Some(&sub))
+        .ok_or_else(|| anyhow!("No healthy upstream"))?;
 
-    let session_inst = session.clone();
+    let mut client = websocket.await?;
 
-    // Forward messages from the server to the client
-    let client_to_server: JoinHandle<Result<()>> = tokio::spawn(async move {
-        let mut receiver = session_inst
-            .read()
-            .or(Err(anyhow!("could not read from RWLock")))?
-            .get_socket_session()
-            .ok_or_else(|| anyhow!("could not get socket session"))?
-            .transmitter
-            .subscribe();
+    let permissions = match current_permissions(session, route) {
+        Ok(permissions) => permissions,
+        Err(reason) => {
+            client.close(close_frame(reason)).await?;
+            return Ok(());
+        }
+    };
+    let mut server = connect(state, route, &guard.endpoint.uri, uri, &permissions).await?;
 
-        loop {
-            tokio::select! {
-                msg = client_read.next() => {
-                    if let Some(msg) = msg {
-                        let msg = msg?;
-                        if session_inst
-                            .read()
-                            .or(Err(anyhow!("could not read from RWLock")))?
-                            .get_access_jwt()
-                            .is_expired()
-                        {
-                            server_write.close().await?;
+    let message_limit = route.and_then(|route| {
+        let limit = route.rate_limit.as_ref()?.websocket_messages.clone()?;
+        Some((format!("{}|ws:{}", route.path, sub), limit))
+    });
+    let expired = || session.load().get_access_jwt().is_expired();
+
+    loop {
+        tokio::select! {
+            msg = server.next() => {
+                let Some(msg) = msg else {
+                    client.close(None).await?;
+                    break;
+                };
+                let msg = msg?;
+                if expired() {
+                    client.close(None).await?;
+                    break;
+                }
+                client.send(msg).await?;
+            },
+            msg = client.next() => {
+                let Some(msg) = msg else {
+                    server.close(None).await?;
+                    break;
+                };
+                let msg = msg?;
+                if expired() {
+                    server.close(None).await?;
+                    break;
+                }
+                // the client is told instead of losing messages without knowing
+                if let Some((key, limit)) = &message_limit {
+                    if !state.rate_limiter.check(key, limit).allowed {
+                        let _ = server.close(None).await;
+                        client.close(close_frame("Too many messages")).await?;
+                        break;
+                    }
+                }
+                server.send(msg).await?;
+            },
+            event = events.recv() => match event {
+                // the upstream only learns permissions when connecting, so it is reconnected
+                Ok(SocketEvent::PermissionsChanged) | Err(RecvError::Lagged(_)) => {
+                    match current_permissions(session, route) {
+                        Ok(permissions) => {
+                            let reconnected =
+                                connect(state, route, &guard.endpoint.uri, uri, &permissions).await?;
+                            let _ = server.close(None).await;
+                            server = reconnected;
+                        }
+                        Err(reason) => {
+                            let _ = server.close(None).await;
+                            client.close(close_frame(reason)).await?;
                             break;
                         }
-                        server_write.send(msg).await?;
                     }
-                },
-                _ = receiver.recv() => {
-                // Handle receiver event if needed
-                    server_write.close().await?;
+                }
+                Ok(SocketEvent::Close(reason)) => {
+                    let _ = server.close(None).await;
+                    client.close(close_frame(&reason)).await?;
                     break;
                 }
-            }
+                Err(RecvError::Closed) => {
+                    let _ = server.close(None).await;
+                    client.close(None).await?;
+                    break;
+                }
+            },
         }
-        Ok(())
-    });
-
-    // Wait for both tasks to complete
-    let _ = tokio::try_join!(client_to_server, server_to_client);
+    }
+    drop(guard);
 
     Ok(())
 }
@@ -123,31 +206,32 @@ fn check_key(
     req: &Request<hyper::body::Incoming>,
     sessions: &Arc<sessions::SafeSessions>,
     config: &Arc<config::Config>,
-) -> Result<Arc<std::sync::RwLock<Session>>> {
+) -> Result<Arc<SharedSession>> {
     sessions
         .get_from_websocket_key(&super::permission::extract_socket_key_from_utl(
             req.uri(),
             &config.socket_encryption_key,
-        )?)?
+        )?)
         .ok_or(anyhow!("Key not found"))
 }
 
 pub async fn handle_web_socket(
     mut req: Request<hyper::body::Incoming>,
-    sessions: &Arc<sessions::SafeSessions>,
-    config: &Arc<config::Config>,
+    state: &Arc<State>,
 ) -> Result<Response<Full<Bytes>>> {
     // Upgrade the connection to a WebSocket connection
 
     // Spawn a new task to handle the WebSocket connection
 
-    let sessions = sessions.clone();
-    let config = config.clone();
+    let state = state.clone();
     let (response, websocket) = hyper_tungstenite::upgrade(&mut req, None)?;
     tokio::spawn(async move {
-        match check_key(&req, &sessions, &config) {
+        let config = &state.config;
+        match check_key(&req, &state.sessions, config) {
             Ok(session) => {
-                if let Err(e) = serve_websocket(websocket, &config, &session).await {
+                let route = routes::find(&config.routes, req.method(), req.uri().path());
+                if let Err(e) = serve_websocket(websocket, &state, route, req.uri(), &session).await
+                {
                     Err(anyhow!("Error closing websocket connection: {e}"))?;
                 }
             }
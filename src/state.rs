@@ -0,0 +1,36 @@
+use std::sync::{atomic::AtomicU64, Arc};
+
+use crate::{
+    circuit_breaker::CircuitBreaker,
+    config,
+    logout::Logout,
+    peers::Peers,
+    permission::{PermissionProvider, PermissionSet},
+    rate_limit::RateLimiter,
+    scheduler::Scheduler,
+    session_store::SessionStore,
+    sessions, upstream,
+    webhook::Webhook,
+};
+
+/// Everything a request needs, shared by every connection.
+pub struct State {
+    pub config: Arc<config::Config>,
+    pub sessions: Arc<sessions::SafeSessions>,
+    pub upstream: Arc<upstream::Upstream>,
+    // also used by `permission_provider`, kept here for the metrics
+    pub permission_breaker: Option<Arc<CircuitBreaker>>,
+    pub rate_limiter: RateLimiter,
+    pub permission_provider: Box<dyn PermissionProvider>,
+    pub webhook: Option<Webhook>,
+    pub logout: Option<Logout>,
+    // session deadlines, see `scheduler::start`
+    pub scheduler: Scheduler,
+    pub session_store: Option<Box<dyn SessionStore>>,
+    pub peers: Option<Peers>,
+    // `config.anonymous_permissions` for requests without a session
+    pub anonymous_permissions: PermissionSet,
+    // lookups that failed and fell back on last known permissions, and requests served with them
+    pub permission_fallbacks: AtomicU64,
+    pub degraded_requests: AtomicU64,
+}
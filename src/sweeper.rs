@@ -0,0 +1,68 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use crate::{
+    peers,
+    session::{SharedSession, SocketEvent},
+    sessions::Eviction,
+    state::State,
+};
+
+/// Closes the websockets of sessions that were dropped, cancels their deadlines, ends them on
+/// the peers and stops permission updates for users left without a session.
+pub async fn end_sessions(state: &State, evicted: Vec<(Eviction, Arc<SharedSession>)>) {
+    let mut ended = Vec::new();
+    for (eviction, session) in evicted {
+        let session = session.load();
+        session.notify_sockets(SocketEvent::Close(eviction.message().to_string()));
+        state
+            .scheduler
+            .cancel_session(session.get_refresh_jwt().get_full_token());
+        // idle and least recently used are what this replica saw, a peer may still be serving it
+        if matches!(eviction, Eviction::Expired | Eviction::Logout) {
+            peers::publish_end(
+                state,
+                session.get_refresh_jwt().get_full_token(),
+                eviction.message(),
+            );
+        }
+        ended.push((
+            session.get_access_jwt().get_payload().sub.clone(),
+            session.get_access_jwt().get_full_token().to_string(),
+            session.get_refresh_jwt().get_full_token().to_string(),
+        ));
+    }
+
+    let Some(webhook) = &state.webhook else {
+        return;
+    };
+    let mut unsubscribed = HashSet::new();
+    for (sub, access_token, refresh_token) in ended {
+        // the user's other sessions still want permission changes
+        if unsubscribed.contains(&sub) || !state.sessions.get_by_sub(&sub).is_empty() {
+            continue;
+        }
+        webhook.unsubscribe(&access_token, &refresh_token).await;
+        unsubscribed.insert(sub);
+    }
+}
+
+/// Sweeps idle sessions every `sessions.sweep_interval_ms`.
+pub fn start(state: Arc<State>) {
+    let period = Duration::from_millis(state.config.sessions.sweep_interval_ms.max(1));
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            end_sessions(&state, state.sessions.sweep()).await;
+        }
+    });
+}
+
+/// Makes room when there are more than `sessions.max_sessions`.
+pub fn enforce_capacity(state: &Arc<State>) {
+    let evicted = state.sessions.evict_least_recent();
+    if !evicted.is_empty() {
+        let state = state.clone();
+        tokio::spawn(async move { end_sessions(&state, evicted).await });
+    }
+}
@@ -0,0 +1,443 @@
+use anyhow::{anyhow, Result};
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    body::{Bytes, Incoming},
+    http::uri::Scheme,
+    Method, Request, Response, StatusCode, Uri,
+};
+use hyper_util::client::legacy::{connect::HttpConnector, Client};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::time::{sleep, timeout, Instant};
+
+use crate::{
+    circuit_breaker::CircuitBreaker,
+    config::{self, Route},
+    permission::PermissionSet,
+    pool::{EndpointGuard, Pool},
+    utils,
+};
+
+pub type HttpClient = Client<HttpConnector, Full<Bytes>>;
+
+fn build_client(connect_timeout: Option<Duration>) -> HttpClient {
+    let mut connector = HttpConnector::new();
+    connector.set_connect_timeout(connect_timeout);
+    Client::builder(hyper_util::rt::TokioExecutor::new()).build(connector)
+}
+
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    )
+}
+
+// the request parts are not `Clone` because of the extensions, which are not needed upstream
+fn clone_request(req: &Request<Full<Bytes>>) -> Request<Full<Bytes>> {
+    let mut clone = Request::new(req.body().clone());
+    *clone.method_mut() = req.method().clone();
+    *clone.uri_mut() = req.uri().clone();
+    *clone.version_mut() = req.version();
+    *clone.headers_mut() = req.headers().clone();
+    clone
+}
+
+// the endpoint's scheme and authority with the request's path and query
+fn endpoint_uri(endpoint: &Uri, uri: &Uri) -> Result<Uri> {
+    Ok(Uri::builder()
+        .scheme(endpoint.scheme().unwrap_or(&Scheme::HTTP).clone())
+        .authority(
+            endpoint
+                .authority()
+                .ok_or_else(|| anyhow!("endpoint has no authority"))?
+                .clone(),
+        )
+        .path_and_query(
+            uri.path_and_query()
+                .map_or("/", |path_and_query| path_and_query.as_str()),
+        )
+        .build()?)
+}
+
+/// Counts requests and retries per second, retries are allowed while they stay under
+/// `ratio` of the requests plus `min_retries_per_second`.
+#[derive(Debug)]
+pub struct RetryBudget {
+    config: config::RetryBudget,
+    window: AtomicU64,
+    requests: AtomicU64,
+    retries: AtomicU64,
+}
+
+impl RetryBudget {
+    pub fn new(config: config::RetryBudget) -> Self {
+        RetryBudget {
+            config,
+            window: AtomicU64::new(utils::get_current_unix_timestamp()),
+            requests: AtomicU64::new(0),
+            retries: AtomicU64::new(0),
+        }
+    }
+
+    fn roll_window(&self, now: u64) {
+        let window = self.window.load(Ordering::Relaxed);
+        if window != now
+            && self
+                .window
+                .compare_exchange(window, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            self.requests.store(0, Ordering::Relaxed);
+            self.retries.store(0, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_request(&self) {
+        self.record_request_at(utils::get_current_unix_timestamp());
+    }
+
+    fn record_request_at(&self, now: u64) {
+        self.roll_window(now);
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn try_withdraw(&self) -> bool {
+        self.try_withdraw_at(utils::get_current_unix_timestamp())
+    }
+
+    fn try_withdraw_at(&self, now: u64) -> bool {
+        self.roll_window(now);
+        let allowed = (self.requests.load(Ordering::Relaxed) as f64 * self.config.ratio) as u64
+            + self.config.min_retries_per_second;
+        self.retries
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |retries| {
+                (retries < allowed).then_some(retries + 1)
+            })
+            .is_ok()
+    }
+}
+
+enum AttemptError {
+    ConnectTimeout,
+    FirstByteTimeout,
+    Client(hyper_util::client::legacy::Error),
+}
+
+// the connector reports running out of `connect_ms` as a connect error caused by `TimedOut`
+fn is_connect_timeout(err: &hyper_util::client::legacy::Error) -> bool {
+    let mut source = std::error::Error::source(err);
+    while let Some(err) = source {
+        if err
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|err| err.kind() == std::io::ErrorKind::TimedOut)
+        {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+impl From<hyper_util::client::legacy::Error> for AttemptError {
+    fn from(err: hyper_util::client::legacy::Error) -> Self {
+        if err.is_connect() && is_connect_timeout(&err) {
+            AttemptError::ConnectTimeout
+        } else {
+            AttemptError::Client(err)
+        }
+    }
+}
+
+pub struct Upstream {
+    default_client: HttpClient,
+    // one client per distinct route connect timeout
+    clients: HashMap<u64, HttpClient>,
+    retry_budget: RetryBudget,
+    // `sidecar_url`
+    default_pool: Arc<Pool>,
+    pools: HashMap<String, Arc<Pool>>,
+}
+
+impl Upstream {
+    pub fn new(config: &config::Config) -> Result<Self> {
+        let clients = config
+            .routes
+            .iter()
+            .filter_map(|route| route.timeouts.connect_ms)
+            .map(|ms| (ms, build_client(Some(Duration::from_millis(ms)))))
+            .collect();
+
+        let pools = config
+            .upstreams
+            .iter()
+            .map(|(name, pool)| {
+                let pool = Pool::new(
+                    name,
+                    pool.clone(),
+                    config.circuit_breakers.upstreams.as_ref(),
+                    config.admission.as_ref(),
+                    config.adaptive_concurrency.as_ref(),
+                )
+                .map_err(|err| anyhow!("upstream {}: {}", name, err))?;
+                Ok((name.clone(), Arc::new(pool)))
+            })
+            .collect::<Result<HashMap<String, Arc<Pool>>>>()?;
+
+        for route in config.routes.iter() {
+            if let Some(name) = &route.upstream {
+                if !pools.contains_key(name) {
+                    return Err(anyhow!(
+                        "route {} uses unknown upstream {}",
+                        route.path,
+                        name
+                    ));
+                }
+            }
+        }
+
+        Ok(Upstream {
+            default_client: build_client(None),
+            clients,
+            retry_budget: RetryBudget::new(config.retry_budget.clone()),
+            default_pool: Arc::new(Pool::single(
+                "sidecar",
+                &config.sidecar_url,
+                config.circuit_breakers.upstreams.as_ref(),
+                config.admission.as_ref(),
+                config.adaptive_concurrency.as_ref(),
+            )?),
+            pools,
+        })
+    }
+
+    pub fn start_health_checks(&self) {
+        for pool in self.pools.values() {
+            pool.start_health_checks(self.default_client.clone());
+        }
+    }
+
+    pub fn pools(&self) -> impl Iterator<Item = &Arc<Pool>> {
+        std::iter::once(&self.default_pool).chain(self.pools.values())
+    }
+
+    fn pool(&self, route: Option<&Route>) -> &Arc<Pool> {
+        route
+            .and_then(|route| route.upstream.as_ref())
+            .and_then(|name| self.pools.get(name))
+            .unwrap_or(&self.default_pool)
+    }
+
+    /// Picks the endpoint a route's traffic should go to, `hash_key` is the user's `sub`.
+    pub fn pick(&self, route: Option<&Route>, hash_key: Option<&str>) -> Option<EndpointGuard> {
+        self.pool(route).pick(hash_key)
+    }
+
+    pub fn breaker(&self, route: Option<&Route>) -> Option<&CircuitBreaker> {
+        self.pool(route).breaker()
+    }
+
+    fn client(&self, route: Option<&Route>) -> &HttpClient {
+        route
+            .and_then(|route| route.timeouts.connect_ms)
+            .and_then(|ms| self.clients.get(&ms))
+            .unwrap_or(&self.default_client)
+    }
+
+    async fn attempt(
+        &self,
+        req: Request<Full<Bytes>>,
+        route: Option<&Route>,
+    ) -> Result<Response<Incoming>, AttemptError> {
+        let first_byte = route.and_then(|route| route.timeouts.first_byte_ms);
+        let response = self.client(route).request(req);
+        match first_byte {
+            Some(ms) => timeout(Duration::from_millis(ms), response)
+                .await
+                .map_err(|_| AttemptError::FirstByteTimeout)?
+                .map_err(AttemptError::from),
+            None => response.await.map_err(AttemptError::from),
+        }
+    }
+
+    async fn send_with_retries(
+        &self,
+        req: Request<Full<Bytes>>,
+        route: Option<&Route>,
+        hash_key: Option<&str>,
+        permissions: &PermissionSet,
+        deadline: Option<Instant>,
+    ) -> Result<Response<Full<Bytes>>> {
+        let pool = self.pool(route);
+
+        // held across the retries, they are the same request to the upstream
+        let _permit = match pool.admission() {
+            Some(admission) => match admission.acquire(admission.priority(permissions)).await {
+                Ok(permit) => Some(permit),
+                Err(rejected) => return Ok(rejected.response()),
+            },
+            None => None,
+        };
+
+        self.retry_budget.record_request();
+
+        let retry = route
+            .and_then(|route| route.retry.as_ref())
+            .filter(|_| is_idempotent(req.method()));
+        let mut attempt = 0;
+
+        loop {
+            let probe = match pool.breaker().map(|breaker| breaker.acquire()) {
+                Some(Ok(probe)) => Some(probe),
+                Some(Err(open)) => return Ok(open.response()),
+                None => None,
+            };
+
+            let permit = match pool.concurrency_limit().map(|limit| limit.try_acquire()) {
+                Some(Ok(permit)) => Some(permit),
+                Some(Err(exceeded)) => {
+                    if let Some(probe) = probe {
+                        probe.cancel();
+                    }
+                    return Ok(exceeded.response());
+                }
+                None => None,
+            };
+
+            // every attempt may land on a different endpoint
+            let Some(guard) = pool.pick(hash_key) else {
+                if let Some(probe) = probe {
+                    probe.release(false);
+                }
+                return Ok(utils::status_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "No healthy upstream",
+                ));
+            };
+
+            let mut endpoint_req = clone_request(&req);
+            *endpoint_req.uri_mut() = endpoint_uri(&guard.endpoint.uri, req.uri())?;
+
+            let result = self.attempt(endpoint_req, route).await;
+            let success = matches!(&result, Ok(response) if !response.status().is_server_error());
+            pool.report(&guard.endpoint, success);
+            if let Some(probe) = probe {
+                probe.release(success);
+            }
+            if let Some(permit) = permit {
+                permit.release(success);
+            }
+            drop(guard);
+
+            let retryable = match &result {
+                Ok(response) => {
+                    retry.is_some_and(|retry| retry.statuses.contains(&response.status().as_u16()))
+                }
+                Err(AttemptError::Client(err)) => err.is_connect(),
+                Err(AttemptError::ConnectTimeout) => true,
+                Err(AttemptError::FirstByteTimeout) => false,
+            };
+
+            if let Some(retry) = retry.filter(|retry| retryable && attempt < retry.attempts) {
+                let wait = utils::backoff(retry.backoff_ms, retry.max_backoff_ms, attempt);
+                let in_time = deadline.is_none_or(|deadline| Instant::now() + wait < deadline);
+                if in_time && self.retry_budget.try_withdraw() {
+                    attempt += 1;
+                    sleep(wait).await;
+                    continue;
+                }
+            }
+
+            return match result {
+                Ok(response) => {
+                    let (parts, body) = response.into_parts();
+                    let body = body.collect().await?.to_bytes();
+                    Ok(Response::from_parts(parts, Full::from(body)))
+                }
+                Err(AttemptError::ConnectTimeout | AttemptError::FirstByteTimeout) => Ok(
+                    utils::status_response(StatusCode::GATEWAY_TIMEOUT, "Gateway Timeout"),
+                ),
+                Err(AttemptError::Client(err)) => {
+                    println!("Error forwarding request: {err:?}");
+                    Err(anyhow!(err))
+                }
+            };
+        }
+    }
+
+    /// Sends the request to the route's upstream applying its admission, timeouts and retries.
+    /// Running out of time gives a 504 and failing to reach the sidecar a 502. The user's
+    /// `permissions` decide its priority when the upstream is at its concurrency limit.
+    pub async fn send(
+        &self,
+        req: Request<Full<Bytes>>,
+        route: Option<&Route>,
+        hash_key: Option<&str>,
+        permissions: &PermissionSet,
+    ) -> Response<Full<Bytes>> {
+        let total = route
+            .and_then(|route| route.timeouts.total_ms)
+            .map(Duration::from_millis);
+
+        let result = match total {
+            Some(total) => {
+                let deadline = Instant::now() + total;
+                match timeout(
+                    total,
+                    self.send_with_retries(req, route, hash_key, permissions, Some(deadline)),
+                )
+                .await
+                {
+                    Ok(result) => result,
+                    Err(_) => {
+                        return utils::status_response(
+                            StatusCode::GATEWAY_TIMEOUT,
+                            "Gateway Timeout",
+                        )
+                    }
+                }
+            }
+            None => {
+                self.send_with_retries(req, route, hash_key, permissions, None)
+                    .await
+            }
+        };
+
+        result.unwrap_or_else(|_| utils::status_response(StatusCode::BAD_GATEWAY, "Bad Gateway"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_budget() {
+        let budget = RetryBudget::new(config::RetryBudget {
+            ratio: 0.5,
+            min_retries_per_second: 1,
+        });
+
+        let now = budget.window.load(Ordering::Relaxed);
+
+        for _ in 0..4 {
+            budget.record_request_at(now);
+        }
+
+        // 4 requests * 0.5 + 1
+        assert!(budget.try_withdraw_at(now));
+        assert!(budget.try_withdraw_at(now));
+        assert!(budget.try_withdraw_at(now));
+        assert!(!budget.try_withdraw_at(now));
+
+        // a new window starts from scratch
+        assert!(budget.try_withdraw_at(now + 1));
+        assert!(!budget.try_withdraw_at(now + 1));
+    }
+}
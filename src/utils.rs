@@ -1,5 +1,8 @@
-use hyper::{header, Request};
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::{body::Bytes, header, Request, Response, StatusCode};
+use rand::Rng;
 use sha256::digest;
+use std::time::Duration;
 use uuid::Uuid;
 
 pub fn get_cookies(req: &Request<hyper::body::Incoming>) -> impl Iterator<Item = &str> {
@@ -7,6 +10,7 @@ pub fn get_cookies(req: &Request<hyper::body::Incoming>) -> impl Iterator<Item =
         .get_all(header::COOKIE)
         .iter()
         .flat_map(|x| x.to_str().unwrap_or("").split(';'))
+        .map(str::trim)
 }
 
 pub fn generate_uuid() -> String {
@@ -25,3 +29,32 @@ pub fn get_current_unix_timestamp() -> u64 {
         .expect("Time went backwards");
     since_the_epoch.as_secs()
 }
+
+pub fn get_current_unix_timestamp_ms() -> u64 {
+    let start = std::time::SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Time went backwards");
+    since_the_epoch.as_millis() as u64
+}
+
+/// Reads at most `limit` bytes of `body`, `None` when it is longer.
+pub async fn read_body(body: hyper::body::Incoming, limit: usize) -> anyhow::Result<Option<Bytes>> {
+    match Limited::new(body, limit).collect().await {
+        Ok(collected) => Ok(Some(collected.to_bytes())),
+        Err(err) if err.downcast_ref::<LengthLimitError>().is_some() => Ok(None),
+        Err(err) => Err(anyhow::anyhow!(err)),
+    }
+}
+
+pub fn status_response(status: StatusCode, message: &str) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::from(message.to_string())));
+    *response.status_mut() = status;
+    response
+}
+
+// full jitter: a random wait between zero and the exponential backoff
+pub fn backoff(base_ms: u64, max_ms: u64, attempt: u32) -> Duration {
+    let max = base_ms.saturating_mul(1 << attempt.min(16)).min(max_ms);
+    Duration::from_millis(rand::thread_rng().gen_range(0..=max))
+}
@@ -0,0 +1,351 @@
+use anyhow::{anyhow, Result};
+use dashmap::DashMap;
+use hmac::{Hmac, Mac};
+use http_body_util::Full;
+use hyper::{body::Bytes, HeaderMap, Method, Request, Response, StatusCode};
+use sha2::Sha256;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use tokio::time::sleep;
+
+use crate::{config, peers, permission, scheduler, session::SocketEvent, state::State, utils};
+
+// unix seconds the event was signed at
+pub const X_WEBHOOK_TIMESTAMP: &str = "x-webhook-timestamp";
+// `sha256=` and the hex HMAC of `{timestamp}.{body}`
+pub const X_WEBHOOK_SIGNATURE: &str = "x-webhook-signature";
+
+// registration is retried until the permission service takes it
+const REGISTER_BACKOFF_MS: u64 = 500;
+const REGISTER_MAX_BACKOFF_MS: u64 = 30000;
+
+// an update is one sub and its permissions, anything longer is refused unread
+const MAX_BODY_BYTES: usize = 16 * 1024;
+
+/// The last sequence taken per `sub`, so a captured event cannot be replayed while its signature
+/// is still fresh.
+#[derive(Default)]
+struct Sequences {
+    // sub -> (last sequence, newest signature timestamp)
+    last: DashMap<String, (u64, u64)>,
+    pruned_at: AtomicU64,
+}
+
+impl Sequences {
+    /// Whether `sequence` is newer than every event taken for `sub`, and takes it if so.
+    fn accept(&self, sub: &str, sequence: u64, signed_at: u64) -> bool {
+        let mut entry = self.last.entry(sub.to_string()).or_insert((0, signed_at));
+        let (last, newest) = entry.value_mut();
+        *newest = (*newest).max(signed_at);
+        // sequences start at 1, so the placeholder never shadows a first event
+        if sequence <= *last {
+            return false;
+        }
+        *last = sequence;
+        true
+    }
+
+    // an entry whose every event fails `verify` as too old has nothing left to replay
+    fn prune(&self, max_age_ms: u64, now: u64) {
+        let max_age = max_age_ms.div_ceil(1000);
+        let pruned_at = self.pruned_at.load(Ordering::Relaxed);
+        if now.saturating_sub(pruned_at) < max_age
+            || self
+                .pruned_at
+                .compare_exchange(pruned_at, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            return;
+        }
+        self.last
+            .retain(|_, (_, newest)| newest.saturating_add(max_age) >= now);
+    }
+}
+
+/// Takes permission changes the permission service pushes, see `config::PermissionWebhook`.
+pub struct Webhook {
+    config: config::PermissionWebhook,
+    secret: Vec<u8>,
+    client: reqwest::Client,
+    register_url: reqwest::Url,
+    unsubscribe_url: reqwest::Url,
+    access_token_jwt_cookie_name: String,
+    refresh_token_jwt_cookie_name: String,
+    sequences: Sequences,
+}
+
+// `name` next to `$PERMISSION_URL` unless configured
+fn service_url(url: &Option<String>, config: &config::Config, name: &str) -> Result<reqwest::Url> {
+    let url = match (url, &config.permission_url) {
+        (Some(url), _) => reqwest::Url::parse(url)?,
+        (None, Some(base)) => reqwest::Url::parse(&base.to_string())?.join(name)?,
+        (None, None) => {
+            return Err(anyhow!(
+                "the permission webhook needs $PERMISSION_URL or the url for {}",
+                name
+            ))
+        }
+    };
+    Ok(url)
+}
+
+/// The `X_WEBHOOK_SIGNATURE` value for `body` signed at `timestamp`.
+pub fn sign(secret: &[u8], timestamp: u64, body: &[u8]) -> Result<String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret)?;
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(body);
+    Ok(format!(
+        "sha256={}",
+        hex::encode(mac.finalize().into_bytes())
+    ))
+}
+
+pub fn verify(
+    secret: &[u8],
+    headers: &HeaderMap,
+    body: &[u8],
+    max_age_ms: u64,
+    now: u64,
+) -> Result<u64> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| anyhow!("{} is missing", name))
+    };
+    let timestamp = header(X_WEBHOOK_TIMESTAMP)?;
+    let signature = header(X_WEBHOOK_SIGNATURE)?
+        .strip_prefix("sha256=")
+        .and_then(|signature| hex::decode(signature).ok())
+        .ok_or_else(|| anyhow!("malformed signature"))?;
+
+    let signed_at = timestamp
+        .parse::<u64>()
+        .map_err(|_| anyhow!("malformed timestamp"))?;
+    if signed_at.abs_diff(now).saturating_mul(1000) > max_age_ms {
+        return Err(anyhow!("event signed at {} is too old", signed_at));
+    }
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret)?;
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    // compares in constant time
+    mac.verify_slice(&signature)
+        .map_err(|_| anyhow!("signature does not match"))?;
+    Ok(signed_at)
+}
+
+impl Webhook {
+    pub fn new(config: &config::Config) -> Result<Option<Self>> {
+        let Some(webhook) = &config.permission_webhook else {
+            return Ok(None);
+        };
+        let secret = std::env::var(&webhook.secret_env)
+            .map_err(|_| anyhow!("${} is not set", webhook.secret_env))?;
+
+        Ok(Some(Webhook {
+            config: webhook.clone(),
+            secret: secret.into_bytes(),
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(5))
+                .build()?,
+            register_url: service_url(&webhook.register_url, config, "set_permission_update_hook")?,
+            unsubscribe_url: service_url(
+                &webhook.unsubscribe_url,
+                config,
+                "permission_user_unsubscribe",
+            )?,
+            access_token_jwt_cookie_name: config.access_token_jwt_cookie_name.clone(),
+            refresh_token_jwt_cookie_name: config.refresh_token_jwt_cookie_name.clone(),
+            sequences: Sequences::default(),
+        }))
+    }
+
+    pub fn matches(&self, method: &Method, path: &str) -> bool {
+        method == Method::POST && path == self.config.path
+    }
+
+    /// Tells the permission service where to send changes, in the background until it answers.
+    pub fn register(&self) {
+        let request = self
+            .client
+            .get(self.register_url.clone())
+            .query(&[("hook", &self.config.public_url)]);
+
+        tokio::spawn(async move {
+            let mut attempt = 0;
+            loop {
+                let Some(request) = request.try_clone() else {
+                    return;
+                };
+                match request.send().await {
+                    Ok(response) if response.status().is_success() => {
+                        println!("Registered the permission update hook");
+                        return;
+                    }
+                    Ok(response) => eprintln!(
+                        "Permission service answered {} registering the hook",
+                        response.status()
+                    ),
+                    Err(err) => eprintln!("Error registering the permission update hook: {}", err),
+                }
+                sleep(utils::backoff(
+                    REGISTER_BACKOFF_MS,
+                    REGISTER_MAX_BACKOFF_MS,
+                    attempt,
+                ))
+                .await;
+                attempt = attempt.saturating_add(1);
+            }
+        });
+    }
+
+    /// Stops the changes for a user whose last session ended.
+    pub async fn unsubscribe(&self, access_token: &str, refresh_token: &str) {
+        let response = self
+            .client
+            .get(self.unsubscribe_url.clone())
+            .header(
+                reqwest::header::COOKIE,
+                format!(
+                    "{}={}; {}={}",
+                    self.access_token_jwt_cookie_name,
+                    access_token,
+                    self.refresh_token_jwt_cookie_name,
+                    refresh_token
+                ),
+            )
+            .send()
+            .await;
+        match response {
+            Ok(response) if response.status().is_success() => (),
+            Ok(response) => eprintln!(
+                "Permission service answered {} unsubscribing",
+                response.status()
+            ),
+            Err(err) => eprintln!("Error unsubscribing from permission updates: {}", err),
+        }
+    }
+
+    /// Applies a pushed change to the cache, the user's sessions and their open websockets.
+    pub async fn handle(
+        &self,
+        req: Request<hyper::body::Incoming>,
+        state: &State,
+    ) -> Result<Response<Full<Bytes>>> {
+        let (parts, body) = req.into_parts();
+        let Some(body) = utils::read_body(body, MAX_BODY_BYTES).await? else {
+            return Ok(utils::status_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Payload Too Large",
+            ));
+        };
+
+        let now = utils::get_current_unix_timestamp();
+        let signed_at = match verify(
+            &self.secret,
+            &parts.headers,
+            &body,
+            self.config.max_age_ms,
+            now,
+        ) {
+            Ok(signed_at) => signed_at,
+            Err(err) => {
+                eprintln!("Refused permission update: {}", err);
+                return Ok(utils::status_response(
+                    StatusCode::UNAUTHORIZED,
+                    "Unauthorized",
+                ));
+            }
+        };
+
+        let update = match permission::parse_update(&String::from_utf8_lossy(&body)) {
+            Ok(update) => update,
+            Err(err) => {
+                return Ok(utils::status_response(
+                    StatusCode::BAD_REQUEST,
+                    &format!("invalid permission update: {:#}", err),
+                ))
+            }
+        };
+
+        self.sequences.prune(self.config.max_age_ms, now);
+        if !self
+            .sequences
+            .accept(&update.sub, update.sequence, signed_at)
+        {
+            eprintln!(
+                "Refused permission update {} for {}: already taken a newer one",
+                update.sequence, update.sub
+            );
+            return Ok(utils::status_response(StatusCode::CONFLICT, "Conflict"));
+        }
+
+        let sub = update.sub;
+        let permissions = state
+            .permission_provider
+            .push(&sub, update.permissions)
+            .await;
+        for session in state.sessions.get_by_sub(&sub) {
+            session.update(|session| session.apply_permissions(permissions.clone()));
+            session
+                .load()
+                .notify_sockets(SocketEvent::PermissionsChanged);
+            scheduler::watch(state, &session);
+            peers::publish(state, &session);
+        }
+
+        Ok(utils::status_response(StatusCode::NO_CONTENT, ""))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn signed(secret: &[u8], timestamp: u64, body: &[u8]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(X_WEBHOOK_TIMESTAMP, HeaderValue::from(timestamp));
+        headers.insert(
+            X_WEBHOOK_SIGNATURE,
+            HeaderValue::from_str(&sign(secret, timestamp, body).unwrap()).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn test_sequences() {
+        let sequences = Sequences::default();
+        assert!(sequences.accept("201944", 1, 1000));
+        assert!(sequences.accept("201944", 3, 1001));
+        // replayed and reordered
+        assert!(!sequences.accept("201944", 3, 1001));
+        assert!(!sequences.accept("201944", 2, 1000));
+        assert!(sequences.accept("other", 1, 1001));
+
+        sequences.prune(300000, 1200);
+        assert_eq!(sequences.last.len(), 2);
+        sequences.prune(300000, 1501);
+        assert_eq!(sequences.last.len(), 0);
+    }
+
+    #[test]
+    fn test_verify() {
+        let body = br#"{"sub": "201944", "permissions": ["cta"]}"#;
+        let headers = signed(b"secret", 1000, body);
+
+        assert_eq!(
+            verify(b"secret", &headers, body, 300000, 1200).unwrap(),
+            1000
+        );
+        assert!(verify(b"other", &headers, body, 300000, 1200).is_err());
+        assert!(verify(b"secret", &headers, b"{}", 300000, 1200).is_err());
+        // replayed later
+        assert!(verify(b"secret", &headers, body, 300000, 1301).is_err());
+        assert!(verify(b"secret", &HeaderMap::new(), body, 300000, 1000).is_err());
+    }
+}